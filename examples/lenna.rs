@@ -1,51 +1,16 @@
-use charity_pixelization::{process_sprite, Color, DitherOptions, I2PState, Sprite};
+use charity_pixelization::{
+    process_sprite, BuiltinPalette, Color, DitherOptions, I2PState, Sprite,
+};
 use image::{GenericImageView, ImageBuffer, Rgba};
 
 fn main() {
-    let palette = [
-        "#6D001AFF",
-        "#BE0039FF",
-        "#FF4500FF",
-        "#FFA800FF",
-        "#FFD635FF",
-        "#FFF8B8FF",
-        "#00A368FF",
-        "#00CC78FF",
-        "#7EED56FF",
-        "#00756FFF",
-        "#009EAAFF",
-        "#00CCC0FF",
-        "#2450A4FF",
-        "#3690EAFF",
-        "#51E9F4FF",
-        "#493AC1FF",
-        "#6A5CFFFF",
-        "#94B3FFFF",
-        "#811E9FFF",
-        "#B44AC0FF",
-        "#E4ABFFFF",
-        "#DE107FFF",
-        "#FF3881FF",
-        "#FF99AAFF",
-        "#6D482FFF",
-        "#9C6926FF",
-        "#FFB470FF",
-        "#000000FF",
-        "#515252FF",
-        "#898D90FF",
-        "#D4D7D9FF",
-        "#FFFFFFFF",
-    ];
     let mut state = I2PState::default();
     state.dither_options(DitherOptions {
         pixel_distance_mode: charity_pixelization::DistanceMode::OKLab,
         pixel_dither_mode: charity_pixelization::DitherMode::Bayer8x8,
         ..Default::default()
     });
-    state.palette(palette
-        .iter()
-        .map(|c| c.parse().unwrap())
-        .collect());
+    state.palette(BuiltinPalette::RPlace2022.hex()).unwrap();
     let image = image::open("lenna.png").unwrap();
     let mut output = Sprite {
         width: image.width() as usize,
@@ -61,8 +26,8 @@ fn main() {
         );
     }
     let input = output.clone();
-    process_sprite(&mut state, &input, &mut output);
-    process_sprite(&mut state, &input, &mut output);
+    process_sprite(&mut state, &input, &mut output).unwrap();
+    process_sprite(&mut state, &input, &mut output).unwrap();
 
     let mut imgbuf: ImageBuffer<Rgba<u8>, Vec<_>> =
         ImageBuffer::new(output.width as u32, output.height as u32);
@@ -0,0 +1,71 @@
+//! times the slider ticks of a live preview on lenna scaled up to 1024x1024. run with
+//! `cargo run --release --example lenna_bench > /dev/null`, the steps print progress to stdout
+
+use std::time::{Duration, Instant};
+
+use charity_pixelization::{
+    process_sprite_indexed, BuiltinPalette, DitherMode, DitherOptions, DistanceMode, I2PState,
+    PreProcessOptions, Sprite,
+};
+use image::imageops::FilterType;
+
+const RUNS: u32 = 20;
+
+fn time(name: &str, state: &mut I2PState, mut tick: impl FnMut(&mut I2PState, u32)) {
+    let mut total = Duration::ZERO;
+    for i in 0..RUNS {
+        tick(state, i);
+        let start = Instant::now();
+        let image = state.image_rgba().unwrap();
+        total += start.elapsed();
+        assert_eq!(image.width(), 1024);
+    }
+    eprintln!("{name:>24}: {:>10.3?} per image", total / RUNS);
+}
+
+fn main() {
+    let image = image::open("lenna.png")
+        .unwrap()
+        .resize_exact(1024, 1024, FilterType::Triangle)
+        .to_rgba8();
+    let input = Sprite::from_rgba(image.as_raw(), 1024, 1024).unwrap();
+
+    let mut state = I2PState::new();
+    state.palette(BuiltinPalette::RPlace2022.hex()).unwrap();
+    state.set_input_rgba(image.as_raw(), 1024, 1024).unwrap();
+    state.dither_options(DitherOptions {
+        pixel_dither_mode: DitherMode::Bayer4x4,
+        pixel_distance_mode: DistanceMode::RGB,
+        ..Default::default()
+    });
+    state.image_rgba().unwrap();
+
+    time("nothing changed", &mut state, |_, _| {});
+    time("outline", &mut state, |state, i| {
+        state.image_outline(Some(i as usize % 4));
+    });
+    time("dither amount", &mut state, |state, i| {
+        state.dither_options(DitherOptions {
+            dither_amount: 32.0 + i as f32,
+            pixel_dither_mode: DitherMode::Bayer4x4,
+            pixel_distance_mode: DistanceMode::RGB,
+            ..Default::default()
+        });
+    });
+    time("brightness", &mut state, |state, i| {
+        state.pre_process_options(PreProcessOptions {
+            brightness: f64::from(i),
+            ..Default::default()
+        });
+    });
+
+    // the steps alone, without turning the result into RGBA
+    let mut total = Duration::ZERO;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        let output = process_sprite_indexed(&mut state, &input, 1024, 1024).unwrap();
+        total += start.elapsed();
+        assert_eq!(output.width, 1024);
+    }
+    eprintln!("{:>24}: {:>10.3?} per call", "cached process_sprite", total / RUNS);
+}
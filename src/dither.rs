@@ -1,18 +1,35 @@
 // because iterators use references, and i REFUSE to use closures
 #![allow(clippy::trivially_copy_pass_by_ref, clippy::module_name_repetitions)]
 
-use std::f64::consts::{PI, TAU};
+use std::{
+    f64::consts::{PI, TAU},
+    sync::Arc,
+};
 
-use ordered_float::OrderedFloat;
-use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use rayon::{
+    iter::{IndexedParallelIterator, ParallelIterator},
+    slice::{ParallelSlice, ParallelSliceMut},
+};
 
-use crate::{Color, Components, I2PState, Sprite};
+use crate::{Color, Components, I2PState, IndexedSprite, TRANSPARENT};
 
 use self::kmeans::dither_kmeans;
+pub(crate) use self::kmeans::kmeans_palette;
+pub(crate) use self::lookup::ClosestLookup;
+pub use self::kmeans::KMeansSeeding;
+pub use self::quantize::{quantize_median_cut, quantize_octree, quantize_wu, PaletteMethod};
+pub use self::subset::select_palette_subset;
+pub(crate) use self::subset::select_palette_subset_with;
 
 use wasm_bindgen::prelude::*;
 
 mod kmeans;
+mod lookup;
+mod quantize;
+mod simd;
+mod subset;
 
 const DITHER_THRESHOLD_BAYER8X8: [f32; 64] = [
     0.0 / 64.0,
@@ -184,9 +201,7 @@ const DITHER_THRESHOLD_CLUSTER4X4: [f32; 16] = [
     14.0 / 16.0,
 ];
 
-type DistanceFunction = dyn Fn(&[Color], &[Components], Color) -> Color + Sync;
-
-#[derive(Default, Clone, Copy)]
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
 #[wasm_bindgen]
 pub enum DitherMode {
     #[default]
@@ -200,7 +215,7 @@ pub enum DitherMode {
     FloydDistributed,
 }
 
-#[derive(Default, PartialEq, Clone, Copy)]
+#[derive(Default, PartialEq, Clone, Copy, Debug)]
 #[wasm_bindgen]
 pub enum DistanceMode {
     KMeans,
@@ -219,65 +234,70 @@ pub enum DistanceMode {
     OKLab,
 }
 
+/// A palette converted into the color space of a distance mode, kept in the state so it isn't
+/// converted again, and the colors already looked up aren't searched again, for every dither.
+#[derive(Clone)]
+pub(crate) struct PaletteTable {
+    palette: Vec<Color>,
+    distance_mode: DistanceMode,
+    pub(crate) closest: Arc<ClosestLookup>,
+}
+
+impl PaletteTable {
+    pub(crate) fn new(palette: &[Color], distance_mode: DistanceMode) -> Self {
+        PaletteTable {
+            palette: palette.to_vec(),
+            distance_mode,
+            closest: Arc::new(ClosestLookup::new(
+                palette.iter().map(color_conversion(distance_mode)).collect(),
+                distance_mode,
+            )),
+        }
+    }
+
+    pub(crate) fn matches(&self, palette: &[Color], distance_mode: DistanceMode) -> bool {
+        self.distance_mode == distance_mode && self.palette == palette
+    }
+}
+
+/// Maps every pixel of `input` to an entry of `palette`, which `closest` searches in the color
+/// space of the distance mode. Pixels below the alpha threshold get index `palette.len()`, which is
+/// a transparent entry in the returned sprite's palette.
 pub fn dither_image(
     state: &mut I2PState,
+    palette: &[Color],
+    closest: &ClosestLookup,
     input: &[Color],
-    output: &mut Sprite,
     width: usize,
     height: usize,
-) {
+) -> IndexedSprite {
+    // every random choice comes from here, so the same options always give the same image
+    state.rng = ChaCha8Rng::seed_from_u64(state.dither_options.seed);
+
     if state.dither_options.pixel_distance_mode == DistanceMode::KMeans {
-        dither_kmeans(state, input, output, width, height);
-        return;
+        let indices = dither_kmeans(state, palette, input, width, height);
+        return IndexedSprite::with_transparent(width, height, indices, palette);
     }
 
-    let palette_components: Vec<Components> = match state.dither_options.pixel_distance_mode {
-        DistanceMode::RGB | DistanceMode::LWRGB | DistanceMode::Redmean => {
-            state.palette.iter().map(color_to_rgb).collect()
-        }
-        DistanceMode::CIE76 | DistanceMode::CIE94 | DistanceMode::CIEDE2000 | DistanceMode::CMC => {
-            state.palette.iter().map(color_to_lab).collect()
-        }
-        DistanceMode::XYZ => state.palette.iter().map(color_to_xyz).collect(),
-        DistanceMode::YCC => state.palette.iter().map(color_to_ycc).collect(),
-        DistanceMode::YIQ => state.palette.iter().map(color_to_yiq).collect(),
-        DistanceMode::YUV => state.palette.iter().map(color_to_yuv).collect(),
-        DistanceMode::OKLab => state.palette.iter().map(color_to_oklab).collect(),
-        DistanceMode::KMeans => unreachable!(),
-    };
-
-    let find_closest = match state.dither_options.pixel_distance_mode {
-        DistanceMode::RGB => palette_find_closest(color_to_rgb, color_dist2),
-        DistanceMode::LWRGB => palette_find_closest(color_to_rgb, lwrgb_color_dist2),
-        DistanceMode::Redmean => palette_find_closest(color_to_rgb, redmean_color_dist2),
-        DistanceMode::CIE76 => palette_find_closest(color_to_lab, color_dist2),
-        DistanceMode::CIE94 => palette_find_closest(color_to_lab, cie94_color_dist2),
-        DistanceMode::CIEDE2000 => palette_find_closest(color_to_lab, ciede2000_color_dist2),
-        DistanceMode::CMC => palette_find_closest(color_to_lab, cmc_color_dist2),
-        DistanceMode::XYZ => palette_find_closest(color_to_xyz, color_dist2),
-        DistanceMode::YCC => palette_find_closest(color_to_ycc, color_dist2),
-        DistanceMode::YIQ => palette_find_closest(color_to_yiq, color_dist2),
-        DistanceMode::YUV => palette_find_closest(color_to_yuv, color_dist2),
-        DistanceMode::OKLab => palette_find_closest(color_to_oklab, color_dist2),
-        DistanceMode::KMeans => unreachable!(),
-    };
+    // the transparent index, validation keeps palettes below u16::MAX entries
+    #[allow(clippy::cast_possible_truncation)]
+    let transparent = palette.len() as u16;
+    let mut indices = vec![transparent; input.len()];
 
     match state.dither_options.pixel_dither_mode {
         DitherMode::None => dither_none(
             state,
             input,
-            &mut output.data,
-            &state.palette,
-            &palette_components,
-            find_closest,
+            &mut indices,
+            transparent,
+            closest,
         ),
         DitherMode::Bayer8x8 => dither_threshold(
             state,
             input,
-            &mut output.data,
-            &state.palette,
-            &palette_components,
-            find_closest,
+            &mut indices,
+            transparent,
+            closest,
             width,
             &DITHER_THRESHOLD_BAYER8X8,
             3,
@@ -285,10 +305,9 @@ pub fn dither_image(
         DitherMode::Bayer4x4 => dither_threshold(
             state,
             input,
-            &mut output.data,
-            &state.palette,
-            &palette_components,
-            find_closest,
+            &mut indices,
+            transparent,
+            closest,
             width,
             &DITHER_THRESHOLD_BAYER4X4,
             2,
@@ -296,10 +315,9 @@ pub fn dither_image(
         DitherMode::Bayer2x2 => dither_threshold(
             state,
             input,
-            &mut output.data,
-            &state.palette,
-            &palette_components,
-            find_closest,
+            &mut indices,
+            transparent,
+            closest,
             width,
             &DITHER_THRESHOLD_BAYER2X2,
             1,
@@ -307,10 +325,9 @@ pub fn dither_image(
         DitherMode::Cluster8x8 => dither_threshold(
             state,
             input,
-            &mut output.data,
-            &state.palette,
-            &palette_components,
-            find_closest,
+            &mut indices,
+            transparent,
+            closest,
             width,
             &DITHER_THRESHOLD_CLUSTER8X8,
             3,
@@ -318,10 +335,9 @@ pub fn dither_image(
         DitherMode::Cluster4x4 => dither_threshold(
             state,
             input,
-            &mut output.data,
-            &state.palette,
-            &palette_components,
-            find_closest,
+            &mut indices,
+            transparent,
+            closest,
             width,
             &DITHER_THRESHOLD_CLUSTER4X4,
             2,
@@ -329,29 +345,42 @@ pub fn dither_image(
         DitherMode::FloydComponent => todo!(),
         DitherMode::FloydDistributed => todo!(),
     }
-}
-
-fn palette_find_closest(
-    conversion: impl Fn(&Color) -> Components + 'static + Sync,
-    distance: impl Fn(&Components, &Components) -> f64 + 'static + Sync,
-) -> Box<DistanceFunction> {
-    Box::new(
-        move |palette: &[Color], palette_components: &[Components], color: Color| {
-            if color.alpha == 0 {
-                return palette[0];
-            }
 
-            let input = conversion(&color);
+    IndexedSprite::with_transparent(width, height, indices, palette)
+}
 
-            let index = palette_components
-                .iter()
-                .enumerate()
-                .min_by_key(|(_, c)| OrderedFloat(distance(&input, c)))
-                .map_or(0, |(i, _)| i);
+/// The color space that `mode` compares colors in.
+pub(crate) fn color_conversion(mode: DistanceMode) -> fn(&Color) -> Components {
+    match mode {
+        DistanceMode::RGB | DistanceMode::LWRGB | DistanceMode::Redmean => color_to_rgb,
+        DistanceMode::CIE76 | DistanceMode::CIE94 | DistanceMode::CIEDE2000 | DistanceMode::CMC => {
+            color_to_lab
+        }
+        DistanceMode::XYZ => color_to_xyz,
+        DistanceMode::YCC => color_to_ycc,
+        DistanceMode::YIQ => color_to_yiq,
+        DistanceMode::YUV => color_to_yuv,
+        DistanceMode::KMeans | DistanceMode::OKLab => color_to_oklab,
+    }
+}
 
-            palette[index]
-        },
-    )
+/// The distance formula of `mode`, taking components from [`color_conversion`].
+pub(crate) fn color_distance(mode: DistanceMode) -> fn(&Components, &Components) -> f64 {
+    match mode {
+        DistanceMode::LWRGB => lwrgb_color_dist2,
+        DistanceMode::Redmean => redmean_color_dist2,
+        DistanceMode::CIE94 => cie94_color_dist2,
+        DistanceMode::CIEDE2000 => ciede2000_color_dist2,
+        DistanceMode::CMC => cmc_color_dist2,
+        DistanceMode::KMeans
+        | DistanceMode::RGB
+        | DistanceMode::CIE76
+        | DistanceMode::XYZ
+        | DistanceMode::YCC
+        | DistanceMode::YIQ
+        | DistanceMode::YUV
+        | DistanceMode::OKLab => color_dist2,
+    }
 }
 
 fn color_dist2(a: &Components, b: &Components) -> f64 {
@@ -519,7 +548,7 @@ fn cmc_color_dist2(col0: &Components, col1: &Components) -> f64 {
     sl.powf(2.0) + sc.powf(2.0) + sh.powf(2.0)
 }
 
-fn color_to_oklab(color: &Color) -> Components {
+pub(crate) fn color_to_oklab(color: &Color) -> Components {
     let r = f64::from(color.red) / 255.0;
     let g = f64::from(color.green) / 255.0;
     let b = f64::from(color.blue) / 255.0;
@@ -555,6 +584,34 @@ fn color_to_oklab(color: &Color) -> Components {
     )
 }
 
+pub(crate) fn oklab_to_color(lab: &Components, alpha: u8) -> Color {
+    let cbrt_l = lab.0 + 0.396_337_777_4 * lab.1 + 0.215_803_757_3 * lab.2;
+    let cbrt_m = lab.0 - 0.105_561_345_8 * lab.1 - 0.063_854_172_8 * lab.2;
+    let cbrt_s = lab.0 - 0.089_484_177_5 * lab.1 - 1.291_485_548_0 * lab.2;
+
+    let l = cbrt_l * cbrt_l * cbrt_l;
+    let m = cbrt_m * cbrt_m * cbrt_m;
+    let s = cbrt_s * cbrt_s * cbrt_s;
+
+    let lr = 4.076_741_662_1 * l - 3.307_711_591_3 * m + 0.230_969_929_2 * s;
+    let lg = -1.268_438_004_6 * l + 2.609_757_401_1 * m - 0.341_319_396_5 * s;
+    let lb = -0.004_196_086_3 * l - 0.703_418_614_7 * m + 1.707_614_701_0 * s;
+
+    let encode = |c: f64| {
+        let c = if c >= 0.003_130_8 {
+            1.055 * c.powf(1.0 / 2.4) - 0.055
+        } else {
+            12.92 * c
+        };
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        {
+            (c * 255.0).round().clamp(0.0, 255.0) as u8
+        }
+    };
+
+    Color::new(encode(lr), encode(lg), encode(lb), alpha)
+}
+
 fn color_to_ycc(color: &Color) -> Components {
     let r = f64::from(color.red);
     let g = f64::from(color.green);
@@ -665,7 +722,7 @@ fn color_to_rgb(color: &Color) -> Components {
 fn dither_none_apply(state: &mut I2PState, input: &[Color], output: &mut [Color]) {
     for (cin, output) in input.iter().zip(output) {
         if cin.alpha < state.dither_options.alpha_threshold {
-            *output = Color::default();
+            *output = TRANSPARENT;
             continue;
         }
 
@@ -677,19 +734,15 @@ fn dither_none_apply(state: &mut I2PState, input: &[Color], output: &mut [Color]
 fn dither_none(
     state: &I2PState,
     input: &[Color],
-    output: &mut [Color],
-    palette: &[Color],
-    palette_components: &[Components],
-    closest: impl Fn(&[Color], &[Components], Color) -> Color,
+    output: &mut [u16],
+    transparent: u16,
+    closest: &ClosestLookup,
 ) {
+    closest.find_all(input, output);
     for (cin, output) in input.iter().zip(output) {
         if cin.alpha < state.dither_options.alpha_threshold {
-            *output = Color::default();
-            continue;
+            *output = transparent;
         }
-
-        *output = closest(palette, palette_components, *cin);
-        output.alpha = 255;
     }
 }
 
@@ -708,7 +761,7 @@ fn dither_threshold_apply(
         for x in 0..width {
             let input = input[y * width + x];
             if input.alpha < state.dither_options.alpha_threshold {
-                output[y * width + x] = Color::default();
+                output[y * width + x] = TRANSPARENT;
                 continue;
             }
 
@@ -738,44 +791,54 @@ fn dither_threshold_apply(
 fn dither_threshold(
     state: &I2PState,
     input: &[Color],
-    output: &mut Vec<Color>,
-    palette: &[Color],
-    palette_components: &[Components],
-    closest: impl Fn(&[Color], &[Components], Color) -> Color + Sync,
+    output: &mut [u16],
+    transparent: u16,
+    closest: &ClosestLookup,
     width: usize,
     threshold: &[f32],
     dim: u8,
 ) {
     let amount = state.dither_options.dither_amount / 1000.0;
 
-    input
-        .par_iter()
+    // a row at a time, so the lookups can be batched
+    output
+        .par_chunks_mut(width)
+        .zip(input.par_chunks(width))
         .enumerate()
-        .map(|(i, input)| {
-            let x = i % width;
-            let y = i / width;
-            if input.alpha < state.dither_options.alpha_threshold {
-                return Color::default();
+        .for_each(|(y, (output, row))| {
+            let colors: Vec<Color> = row
+                .iter()
+                .enumerate()
+                .map(|(x, input)| {
+                    let r#mod = (1 << dim) - 1;
+                    let threshold_id = ((y & r#mod) << dim) + (x & r#mod);
+                    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+                    Color::new(
+                        0x0.max(0xff.min(
+                            (f32::from(input.red)
+                                + 255.0 * amount * (threshold[threshold_id] - 0.5))
+                                as u8,
+                        )),
+                        0x0.max(0xff.min(
+                            (f32::from(input.green)
+                                + 255.0 * amount * (threshold[threshold_id] - 0.5))
+                                as u8,
+                        )),
+                        0x0.max(0xff.min(
+                            (f32::from(input.blue)
+                                + 255.0 * amount * (threshold[threshold_id] - 0.5))
+                                as u8,
+                        )),
+                        255,
+                    )
+                })
+                .collect();
+
+            closest.find_all(&colors, output);
+            for (input, output) in row.iter().zip(output) {
+                if input.alpha < state.dither_options.alpha_threshold {
+                    *output = transparent;
+                }
             }
-
-            let r#mod = (1 << dim) - 1;
-            let threshold_id = ((y & r#mod) << dim) + (x & r#mod);
-            #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
-            let c = Color::new(
-                0x0.max(0xff.min(
-                    (f32::from(input.red) + 255.0 * amount * (threshold[threshold_id] - 0.5)) as u8,
-                )),
-                0x0.max(0xff.min(
-                    (f32::from(input.green) + 255.0 * amount * (threshold[threshold_id] - 0.5))
-                        as u8,
-                )),
-                0x0.max(0xff.min(
-                    (f32::from(input.blue) + 255.0 * amount * (threshold[threshold_id] - 0.5))
-                        as u8,
-                )),
-                255,
-            );
-            closest(palette, palette_components, c)
-        })
-        .collect_into_vec(output);
+        });
 }
@@ -1,25 +1,51 @@
-use rand::{seq::SliceRandom, thread_rng};
+use std::collections::{hash_map::Entry, HashMap};
 
-use crate::{Color, DitherMode, I2PState, Sprite};
+use rand::{
+    distributions::{Distribution, WeightedIndex},
+    seq::SliceRandom,
+    Rng,
+};
+use rayon::{
+    iter::{
+        IndexedParallelIterator, IntoParallelRefIterator, IntoParallelRefMutIterator,
+        ParallelIterator,
+    },
+    slice::{ParallelSlice, ParallelSliceMut},
+};
+
+use crate::{Color, Components, DistanceMode, DitherMode, I2PState, TRANSPARENT};
 
 use super::{
-    dither_none_apply, dither_threshold_apply, DITHER_THRESHOLD_BAYER2X2,
-    DITHER_THRESHOLD_BAYER4X4, DITHER_THRESHOLD_BAYER8X8, DITHER_THRESHOLD_CLUSTER4X4,
-    DITHER_THRESHOLD_CLUSTER8X8,
+    color_conversion, color_distance, color_to_oklab, dither_none_apply, dither_threshold_apply,
+    oklab_to_color, DITHER_THRESHOLD_BAYER2X2, DITHER_THRESHOLD_BAYER4X4,
+    DITHER_THRESHOLD_BAYER8X8, DITHER_THRESHOLD_CLUSTER4X4, DITHER_THRESHOLD_CLUSTER8X8,
 };
 
+use wasm_bindgen::prelude::*;
+
+#[derive(Default, Clone, Copy)]
+#[wasm_bindgen]
+pub enum KMeansSeeding {
+    /// Every seed is a random pixel of the image.
+    Random,
+    #[default]
+    PlusPlus,
+}
+
+/// Palette indices for every pixel, with `palette.len()` for the transparent ones.
 pub(super) fn dither_kmeans(
     state: &mut I2PState,
+    palette: &[Color],
     input: &[Color],
-    output: &mut Sprite,
     width: usize,
     height: usize,
-) {
+) -> Vec<u16> {
+    let mut dithered = vec![TRANSPARENT; input.len()];
     match state.dither_options.pixel_dither_mode {
         DitherMode::Bayer8x8 => dither_threshold_apply(
             state,
             input,
-            &mut output.data,
+            &mut dithered,
             width,
             height,
             &DITHER_THRESHOLD_BAYER8X8,
@@ -28,7 +54,7 @@ pub(super) fn dither_kmeans(
         DitherMode::Bayer4x4 => dither_threshold_apply(
             state,
             input,
-            &mut output.data,
+            &mut dithered,
             width,
             height,
             &DITHER_THRESHOLD_BAYER4X4,
@@ -37,7 +63,7 @@ pub(super) fn dither_kmeans(
         DitherMode::Bayer2x2 => dither_threshold_apply(
             state,
             input,
-            &mut output.data,
+            &mut dithered,
             width,
             height,
             &DITHER_THRESHOLD_BAYER2X2,
@@ -46,7 +72,7 @@ pub(super) fn dither_kmeans(
         DitherMode::Cluster8x8 => dither_threshold_apply(
             state,
             input,
-            &mut output.data,
+            &mut dithered,
             width,
             height,
             &DITHER_THRESHOLD_CLUSTER8X8,
@@ -55,179 +81,361 @@ pub(super) fn dither_kmeans(
         DitherMode::Cluster4x4 => dither_threshold_apply(
             state,
             input,
-            &mut output.data,
+            &mut dithered,
             width,
             height,
             &DITHER_THRESHOLD_CLUSTER4X4,
             2,
         ),
         DitherMode::None | DitherMode::FloydDistributed | DitherMode::FloydComponent => {
-            dither_none_apply(state, input, &mut output.data);
+            dither_none_apply(state, input, &mut dithered);
         }
     }
 
-    let temp = output.clone();
-    let assignments = quant_compute_kmeans(state, &temp, 1);
+    let settings = QuantSettings {
+        palette,
+        pal_in: 1,
+        palette_weight: state.palette_weight,
+        distance_mode: DistanceMode::KMeans,
+        max_iter: state.dither_options.kmeans_max_iterations,
+    };
+    let histogram = QuantHistogram::new(&dithered);
+    let mut centroids = palette.to_vec();
+    let assignments = quant_compute_kmeans(&settings, &mut centroids, &histogram, &mut state.rng);
 
-    for (col, assignment) in output.data.iter_mut().zip(assignments) {
-        if col.alpha == 0 {
-            continue;
-        }
+    // palettes are validated to have fewer than u16::MAX entries
+    #[allow(clippy::cast_possible_truncation)]
+    dithered
+        .iter()
+        .zip(&histogram.pixels)
+        .map(|(col, &unique)| {
+            if col.alpha == 0 {
+                palette.len() as u16
+            } else {
+                assignments[unique as usize] as u16
+            }
+        })
+        .collect()
+}
 
-        *col = state.palette[assignment];
+/// Runs a free k-means over the visible pixels of `data` and returns the `k` centroids.
+pub(crate) fn kmeans_palette(
+    data: &[Color],
+    k: usize,
+    seeding: KMeansSeeding,
+    distance_mode: DistanceMode,
+    max_iter: usize,
+    rng: &mut impl Rng,
+) -> Vec<Color> {
+    let data: Vec<Color> = data.iter().filter(|c| c.alpha != 0).copied().collect();
+    if data.is_empty() || k == 0 {
+        return Vec::new();
     }
+
+    let histogram = QuantHistogram::new(&data);
+    let mut centroids = match seeding {
+        KMeansSeeding::Random => (0..k)
+            .map(|_| quant_pick_random_color(&histogram, rng))
+            .collect(),
+        KMeansSeeding::PlusPlus => quant_seed_plus_plus(&histogram, k, distance_mode, rng),
+    };
+    let settings = QuantSettings {
+        palette: &[],
+        pal_in: 0,
+        palette_weight: 0.0,
+        distance_mode,
+        max_iter,
+    };
+    quant_compute_kmeans(&settings, &mut centroids, &histogram, rng);
+
+    centroids
 }
 
-fn quant_compute_kmeans(state: &mut I2PState, data: &Sprite, pal_in: i32) -> Vec<usize> {
-    let mut quant_cluster_list = vec![Vec::default(); state.palette.len()];
-    let mut quant_centroid_list = vec![Color::default(); state.palette.len()];
-    let mut quant_assignment = vec![0; data.width * data.height];
-    let mut iter = 0;
-    let max_iter = 16;
-    let mut previous_variance = vec![1.0; state.palette.len()];
-    let mut variance: f64;
-    let mut delta: f64;
-    let mut delta_max: f64 = 0.0;
-    let threshold = 0.00005;
-
-    loop {
-        quant_get_cluster_centroid(
-            state,
-            &quant_cluster_list,
-            &mut quant_centroid_list,
-            data,
-            pal_in,
-            1 << state.palette_weight,
-        );
-        quant_cluster_list.shrink_to(0);
-        quant_cluster_list.resize(state.palette.len(), Vec::default());
-        for i in 0..data.width * data.height {
-            let color = data.data[i];
-            quant_assignment[i] = quant_nearest_color_idx(color, &quant_centroid_list);
-            quant_cluster_list[quant_assignment[i]].push(color);
+struct QuantSettings<'a> {
+    /// Anchors the centroids are pulled towards when `pal_in` is set.
+    palette: &'a [Color],
+    pal_in: i32,
+    /// How far each centroid gets pulled from its cluster's mean towards its anchor, from 0 (not
+    /// at all) to 1 (the centroid stays on the anchor).
+    palette_weight: f64,
+    distance_mode: DistanceMode,
+    max_iter: usize,
+}
+
+/// The distinct colors of an image. Clustering runs over these instead of the raw pixels, which
+/// is a lot less work on pixel art and on anything that went through a dither already.
+struct QuantHistogram {
+    /// In order of first appearance, so the result doesn't depend on hashing.
+    colors: Vec<Color>,
+    /// How many pixels have each color. Fully transparent colors get a weight of zero, they get
+    /// assigned but don't pull on the centroids.
+    weights: Vec<f64>,
+    /// Index into `colors` for every pixel.
+    pixels: Vec<u32>,
+}
+
+impl QuantHistogram {
+    #[allow(clippy::cast_possible_truncation)]
+    fn new(data: &[Color]) -> Self {
+        let mut lookup: HashMap<[u8; 4], u32> = HashMap::new();
+        let mut colors = Vec::new();
+        let mut weights: Vec<f64> = Vec::new();
+        let mut pixels = Vec::with_capacity(data.len());
+
+        for color in data {
+            let key = [color.red, color.green, color.blue, color.alpha];
+            let idx = match lookup.entry(key) {
+                Entry::Occupied(entry) => *entry.get(),
+                Entry::Vacant(entry) => {
+                    colors.push(*color);
+                    weights.push(0.0);
+                    *entry.insert((colors.len() - 1) as u32)
+                }
+            };
+            if color.alpha != 0 {
+                weights[idx as usize] += 1.0;
+            }
+            pixels.push(idx);
+        }
+
+        QuantHistogram {
+            colors,
+            weights,
+            pixels,
         }
+    }
+}
+
+// colors per block when summing up the clusters. the blocks are fixed so the floating point sums
+// come out the same no matter how many threads there are
+const QUANT_CHUNK: usize = 4096;
 
-        for i in 0..state.palette.len() {
-            variance = quant_colors_variance(&quant_cluster_list[i]);
-            delta = (previous_variance[i] - variance).abs();
-            delta_max = delta_max.max(delta);
-            previous_variance[i] = variance;
+/// Lloyd's algorithm. Centroids are kept as floats in `OKLab`, where averaging colors makes sense,
+/// while colors get assigned using the distance of `settings.distance_mode`. Stops once no color
+/// changes cluster, or after `settings.max_iter` assignments.
+///
+/// Returns the cluster of every color in `histogram.colors`.
+fn quant_compute_kmeans(
+    settings: &QuantSettings,
+    quant_centroid_list: &mut [Color],
+    histogram: &QuantHistogram,
+    rng: &mut impl Rng,
+) -> Vec<usize> {
+    let k = quant_centroid_list.len();
+    let n = histogram.colors.len();
+    if k == 0 {
+        return vec![0; n];
+    }
+
+    let conversion = color_conversion(settings.distance_mode);
+    let distance = color_distance(settings.distance_mode);
+    let in_oklab = matches!(
+        settings.distance_mode,
+        DistanceMode::KMeans | DistanceMode::OKLab
+    );
+
+    let data_lab: Vec<Components> = histogram.colors.par_iter().map(color_to_oklab).collect();
+    let data_components: Vec<Components> = if in_oklab {
+        Vec::new()
+    } else {
+        histogram.colors.par_iter().map(conversion).collect()
+    };
+    let data_components = if in_oklab {
+        &data_lab
+    } else {
+        &data_components
+    };
+    let anchors: Vec<Components> = settings.palette.iter().map(color_to_oklab).collect();
+
+    // everything the iterations write to is allocated once up front
+    let mut centroids: Vec<Components> = quant_centroid_list.iter().map(color_to_oklab).collect();
+    let mut centroid_components: Vec<Components> = Vec::with_capacity(k);
+    let mut quant_assignment = vec![usize::MAX; n];
+    let mut quant_error = vec![0.0; n];
+    let mut partial_sums = vec![[0.0; 4]; n.div_ceil(QUANT_CHUNK) * k];
+    let mut sums = vec![[0.0; 4]; k];
+
+    for iter in 1..=settings.max_iter.max(1) {
+        centroid_components.clear();
+        if in_oklab {
+            centroid_components.extend(centroids.iter().map(|c| Components(c.0, c.1, c.2)));
+        } else {
+            centroid_components.extend(
+                centroids
+                    .iter()
+                    .map(|c| conversion(&oklab_to_color(c, 255))),
+            );
         }
 
-        iter += 1;
-        if delta_max < threshold || iter > max_iter {
+        let changed = quant_assignment
+            .par_iter_mut()
+            .zip(quant_error.par_iter_mut())
+            .zip(data_components.par_iter())
+            .zip(histogram.weights.par_iter())
+            .map(|(((assignment, error), components), weight)| {
+                let (idx, dist) =
+                    quant_nearest_color_idx(components, &centroid_components, distance);
+                let changed = *assignment != idx;
+                *assignment = idx;
+                *error = dist.max(0.0) * weight;
+                changed
+            })
+            .reduce(|| false, |a, b| a || b);
+
+        if !changed || iter == settings.max_iter.max(1) {
             break;
         }
-    }
 
-    quant_assignment
-}
+        partial_sums
+            .par_chunks_mut(k)
+            .zip(quant_assignment.par_chunks(QUANT_CHUNK))
+            .zip(data_lab.par_chunks(QUANT_CHUNK))
+            .zip(histogram.weights.par_chunks(QUANT_CHUNK))
+            .for_each(|(((block, assignment), lab), weights)| {
+                block.fill([0.0; 4]);
+                for ((cluster, lab), weight) in assignment.iter().zip(lab).zip(weights) {
+                    let sum = &mut block[*cluster];
+                    sum[0] += lab.0 * weight;
+                    sum[1] += lab.1 * weight;
+                    sum[2] += lab.2 * weight;
+                    sum[3] += weight;
+                }
+            });
 
-fn quant_colors_variance(color_list: &[Color]) -> f64 {
-    let length = color_list.len();
-    let mean = quant_colors_mean(color_list, Color::new(0, 0, 0, 0), 0);
-    let dist_sum: f64 = color_list
-        .iter()
-        .map(|c| {
-            let dist = quant_distance(*c, mean);
-            dist * dist
-        })
-        .sum();
+        sums.fill([0.0; 4]);
+        for block in partial_sums.chunks(k) {
+            for (sum, part) in sums.iter_mut().zip(block) {
+                sum[0] += part[0];
+                sum[1] += part[1];
+                sum[2] += part[2];
+                sum[3] += part[3];
+            }
+        }
 
-    #[allow(clippy::cast_precision_loss)]
-    {
-        dist_sum / length as f64
+        quant_get_cluster_centroid(
+            settings,
+            &mut centroids,
+            &anchors,
+            &sums,
+            &data_lab,
+            &mut quant_error,
+            rng,
+        );
+    }
+
+    for (color, centroid) in quant_centroid_list.iter_mut().zip(&centroids) {
+        *color = oklab_to_color(centroid, 255);
     }
+
+    quant_assignment
 }
 
-fn quant_nearest_color_idx(color: Color, color_list: &[Color]) -> usize {
+fn quant_nearest_color_idx(
+    color: &Components,
+    color_list: &[Components],
+    distance: fn(&Components, &Components) -> f64,
+) -> (usize, f64) {
     let mut dist_min = f64::MAX;
     let mut dist: f64;
     let mut idx = 0;
 
     for (i, list_col) in color_list.iter().enumerate() {
-        dist = quant_distance(color, *list_col);
+        dist = distance(color, list_col);
         if dist < dist_min {
             dist_min = dist;
             idx = i;
         }
     }
 
-    idx
-}
-
-fn quant_distance(color0: Color, color1: Color) -> f64 {
-    let mr = 0.5 * (f64::from(color0.red) + f64::from(color1.red));
-    let dr = f64::from(color0.red) - f64::from(color1.red);
-    let dg = f64::from(color0.green) - f64::from(color1.green);
-    let db = f64::from(color0.blue) - f64::from(color1.blue);
-    let distance = (2.0 * dr * dr)
-        + (4.0 * dg * dg)
-        + (3.0 * db * db)
-        + (mr * ((dr * dr) - (db * db)) / 256.0);
-    distance.sqrt() / (3.0 * 255.0)
+    (idx, dist_min)
 }
 
+/// Moves every centroid to the mean of its cluster, from the weighted `OKLab` sums and pixel
+/// counts in `sums`.
 fn quant_get_cluster_centroid(
-    state: &mut I2PState,
-    quant_cluster_list: &[Vec<Color>],
-    quant_centroid_list: &mut [Color],
-    data: &Sprite,
-    pal_in: i32,
-    palette_weight: i32,
+    settings: &QuantSettings,
+    centroids: &mut [Components],
+    anchors: &[Components],
+    sums: &[[f64; 4]],
+    data_lab: &[Components],
+    error: &mut [f64],
+    rng: &mut impl Rng,
 ) {
-    for i in 0..state.palette.len() {
-        if !quant_cluster_list[i].is_empty() {
-            if pal_in != 0 {
-                quant_centroid_list[i] =
-                    quant_colors_mean(&quant_cluster_list[i], state.palette[i], palette_weight);
+    let strength = settings.palette_weight.clamp(0.0, 1.0);
+    for (i, centroid) in centroids.iter_mut().enumerate() {
+        let [l, a, b, count] = sums[i];
+        *centroid = if count > 0.0 {
+            let mean = Components(l / count, a / count, b / count);
+            if settings.pal_in != 0 {
+                let anchor = &anchors[i];
+                Components(
+                    mean.0 + (anchor.0 - mean.0) * strength,
+                    mean.1 + (anchor.1 - mean.1) * strength,
+                    mean.2 + (anchor.2 - mean.2) * strength,
+                )
             } else {
-                quant_centroid_list[i] =
-                    quant_colors_mean(&quant_cluster_list[i], Color::new(0, 0, 0, 0), 0);
+                mean
             }
-        } else if pal_in != 0 {
-            quant_centroid_list[i] = state.palette[i];
+        } else if settings.pal_in != 0 {
+            Components(anchors[i].0, anchors[i].1, anchors[i].2)
         } else {
-            quant_centroid_list[i] = quant_pick_random_color(data);
-        }
+            // an empty cluster gets restarted on a color that's badly served by the others,
+            // picked like a k-means++ seed. that color won't be picked again this round
+            let pick = match WeightedIndex::new(&*error) {
+                Ok(weights) => weights.sample(rng),
+                Err(_) => rng.gen_range(0..data_lab.len()),
+            };
+            error[pick] = 0.0;
+            Components(data_lab[pick].0, data_lab[pick].1, data_lab[pick].2)
+        };
     }
 }
 
-fn quant_pick_random_color(data: &Sprite) -> Color {
-    data.data
-        .as_slice()
-        .choose(&mut thread_rng())
-        .copied()
-        .unwrap_or_default()
+/// A random pixel of the image, so common colors are more likely.
+fn quant_pick_random_color(histogram: &QuantHistogram, rng: &mut impl Rng) -> Color {
+    match WeightedIndex::new(&histogram.weights) {
+        Ok(weights) => histogram.colors[weights.sample(rng)],
+        Err(_) => histogram.colors.choose(rng).copied().unwrap_or_default(),
+    }
 }
 
-#[allow(clippy::cast_possible_truncation, clippy::cast_possible_wrap)]
-fn quant_colors_mean(color_list: &[Color], color: Color, palette_weight: i32) -> Color {
-    let mut r = 0;
-    let mut g = 0;
-    let mut b = 0;
+/// k-means++: every further seed is picked with a probability proportional to its distance from
+/// the closest seed picked so far, which spreads the seeds out over the image.
+fn quant_seed_plus_plus(
+    histogram: &QuantHistogram,
+    k: usize,
+    distance_mode: DistanceMode,
+    rng: &mut impl Rng,
+) -> Vec<Color> {
+    let conversion = color_conversion(distance_mode);
+    let distance = color_distance(distance_mode);
+    let data_components: Vec<Components> = histogram.colors.par_iter().map(conversion).collect();
 
-    for color in color_list {
-        r += i32::from(color.red);
-        g += i32::from(color.green);
-        b += i32::from(color.blue);
-    }
+    let first = quant_pick_random_color(histogram, rng);
+    let first_components = conversion(&first);
+    let mut nearest: Vec<f64> = data_components
+        .par_iter()
+        .zip(histogram.weights.par_iter())
+        .map(|(c, weight)| distance(c, &first_components).max(0.0) * weight)
+        .collect();
+    let mut seeds = vec![first];
 
-    let mut weight_color = palette_weight;
-    if weight_color != 0 {
-        weight_color = color_list.len() as i32 / weight_color;
-    }
-    let length = color_list.len() as i32 + weight_color;
-    r += i32::from(color.red) * weight_color;
-    g += i32::from(color.green) * weight_color;
-    b += i32::from(color.blue) * weight_color;
-
-    if length != 0 {
-        r /= length;
-        g /= length;
-        b /= length;
+    while seeds.len() < k {
+        // fewer distinct colors than seeds leaves every weight at zero
+        let seed = match WeightedIndex::new(&nearest) {
+            Ok(weights) => histogram.colors[weights.sample(rng)],
+            Err(_) => quant_pick_random_color(histogram, rng),
+        };
+        let seed_components = conversion(&seed);
+        nearest
+            .par_iter_mut()
+            .zip(data_components.par_iter())
+            .zip(histogram.weights.par_iter())
+            .for_each(|((d, c), weight)| {
+                *d = d.min(distance(c, &seed_components).max(0.0) * weight);
+            });
+        seeds.push(seed);
     }
 
-    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
-    Color::new(r as u8, g as u8, b as u8, 0)
+    seeds
 }
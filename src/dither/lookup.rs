@@ -0,0 +1,553 @@
+// finding the closest palette entry to a color. converting the color for the distance mode is most
+// of the work, so answers are memoized per exact color. past that, the modes whose distance is a
+// norm of a linear function of RGB get a coarse grid over RGB, where every cell lists the only
+// entries that can be closest to a color inside it. large palettes in the other euclidean modes get
+// a k-d tree, and huge ones that the grid would take too long to build for a k-d tree or a vp-tree.
+// the rest of the formulas aren't metrics, so nothing can be skipped and they scan. batches of
+// colors in modes with a SIMD conversion skip all of that for an approximate scan first, which
+// rules out every entry but the closest one unless another is within its error
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use ordered_float::OrderedFloat;
+
+use crate::{Color, Components};
+
+use self::kd_tree::KdTree;
+use self::vp_tree::VpTree;
+use super::simd::{batch_conversion, BatchConversion, Planes};
+use super::{color_conversion, color_distance, DistanceMode};
+
+mod kd_tree;
+mod vp_tree;
+
+const MEMO_BITS: u32 = 16;
+// set on memo entries that hold an answer, a zeroed entry is empty
+const MEMO_FILLED: u64 = 1 << 63;
+
+const GRID_BITS: u32 = 4;
+const GRID_CELL: u8 = 1 << (8 - GRID_BITS);
+// with fewer entries than this, checking them all is as quick as looking up the cell
+const GRID_MIN_PALETTE: usize = 16;
+// past this many entries, building the grid takes longer than it saves
+const GRID_MAX_PALETTE: usize = 4096;
+// from this many entries, descending a tree beats a scan
+const TREE_MIN_PALETTE: usize = 64;
+// past this many entries, even a SIMD scan is slower than a tree
+const APPROXIMATE_MAX_PALETTE: usize = 1024;
+
+/// A palette in the color space of a distance mode, with what's needed to search it quickly. Gives
+/// the same index as checking every entry in order, the first one on ties.
+pub(crate) struct ClosestLookup {
+    conversion: fn(&Color) -> Components,
+    distance: fn(&Components, &Components) -> f64,
+    pub(crate) components: Vec<Components>,
+    search: Search,
+    approximate: Option<Approximate>,
+    memo: Vec<AtomicU64>,
+}
+
+/// The palette for approximate scans in f32, and how far off their distances can be.
+struct Approximate {
+    conversion: BatchConversion,
+    palette: Planes,
+    tolerance: f32,
+}
+
+enum Search {
+    Scan,
+    Grid(Grid),
+    KdTree(KdTree),
+    VpTree(VpTree),
+}
+
+/// The closest entry offered so far, the first one among equally close entries like a scan in
+/// palette order finds.
+pub(super) struct Closest {
+    pub(super) distance: f64,
+    entry: u16,
+}
+
+impl Closest {
+    pub(super) fn offer(&mut self, distance: f64, entry: u16) {
+        if (OrderedFloat(distance), entry) < (OrderedFloat(self.distance), self.entry) {
+            self.distance = distance;
+            self.entry = entry;
+        }
+    }
+}
+
+/// Cell `i` of the grid can only be closest to entries `candidates[offsets[i]..offsets[i + 1]]`,
+/// which are in palette order.
+struct Grid {
+    offsets: Vec<u32>,
+    candidates: Vec<u16>,
+}
+
+impl ClosestLookup {
+    pub(crate) fn new(components: Vec<Components>, distance_mode: DistanceMode) -> Self {
+        let conversion = color_conversion(distance_mode);
+        let distance = color_distance(distance_mode);
+        let len = components.len();
+        let search = if (GRID_MIN_PALETTE..=GRID_MAX_PALETTE).contains(&len)
+            && is_linear_norm(distance_mode)
+        {
+            Search::Grid(Grid::new(&components, conversion, distance))
+        } else if len >= TREE_MIN_PALETTE && is_euclidean(distance_mode) {
+            Search::KdTree(KdTree::new(&components))
+        } else if len >= TREE_MIN_PALETTE && is_metric(distance_mode) {
+            Search::VpTree(VpTree::new(&components, distance))
+        } else {
+            Search::Scan
+        };
+        let approximate = batch_conversion(distance_mode)
+            .filter(|_| (2..=APPROXIMATE_MAX_PALETTE).contains(&len))
+            .map(|(conversion, tolerance)| Approximate {
+                conversion,
+                palette: Planes::palette(&components),
+                tolerance,
+            });
+
+        ClosestLookup {
+            conversion,
+            distance,
+            components,
+            search,
+            approximate,
+            memo: (0..1 << MEMO_BITS).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    /// The lookup for the entries of this palette at `subset`, indexed by their position there.
+    pub(crate) fn subset(&self, subset: &[usize], distance_mode: DistanceMode) -> Self {
+        ClosestLookup::new(
+            subset.iter().map(|i| self.components[*i]).collect(),
+            distance_mode,
+        )
+    }
+
+    /// The index of the palette entry closest to each of `colors`, 0 for fully transparent ones.
+    pub(crate) fn find_all(&self, colors: &[Color], indices: &mut [u16]) {
+        let Some(approximate) = &self.approximate else {
+            for (color, index) in colors.iter().zip(indices) {
+                *index = self.find(*color);
+            }
+            return;
+        };
+
+        let mut missing = Vec::new();
+        for (i, (color, index)) in colors.iter().zip(indices.iter_mut()).enumerate() {
+            match self.remembered(*color) {
+                Some(found) => *index = found,
+                None => missing.push(i),
+            }
+        }
+        if missing.is_empty() {
+            return;
+        }
+
+        let missing_colors: Vec<Color> = missing.iter().map(|i| colors[*i]).collect();
+        let converted = (approximate.conversion)(&missing_colors);
+        let mut distances = Vec::new();
+        let mut candidates = Vec::new();
+        for (k, (i, color)) in missing.iter().zip(&missing_colors).enumerate() {
+            approximate
+                .palette
+                .distances2(converted.get(k), &mut distances);
+            // the closest entry is within twice the error of the one that looks closest
+            let nearest = distances.iter().copied().fold(f32::INFINITY, f32::min);
+            let limit = nearest.sqrt() + 2.0 * approximate.tolerance;
+            candidates.clear();
+            candidates
+                .extend((0..self.components.len()).filter(|e| distances[*e] <= limit * limit));
+
+            let index = match candidates[..] {
+                // palettes are validated to have fewer than u16::MAX entries
+                #[allow(clippy::cast_possible_truncation)]
+                [only] => only as u16,
+                _ => self.closest_of(&(self.conversion)(color), &mut candidates.iter().copied()),
+            };
+            self.remember(*color, index);
+            indices[*i] = index;
+        }
+    }
+
+    /// The index of the palette entry closest to `color`, 0 for fully transparent colors.
+    fn find(&self, color: Color) -> u16 {
+        if let Some(index) = self.remembered(color) {
+            return index;
+        }
+
+        let index = self.search(color);
+        self.remember(color, index);
+        index
+    }
+
+    fn memo_slot(&self, color: Color) -> (u64, &AtomicU64) {
+        let key = u64::from(color.red) << 16 | u64::from(color.green) << 8 | u64::from(color.blue);
+        // fibonacci hashing, the top bits of the product pick the slot
+        #[allow(clippy::cast_possible_truncation)]
+        let slot =
+            &self.memo[(key.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> (64 - MEMO_BITS)) as usize];
+        (key, slot)
+    }
+
+    fn remembered(&self, color: Color) -> Option<u16> {
+        if color.alpha == 0 {
+            return Some(0);
+        }
+
+        let (key, slot) = self.memo_slot(color);
+        let entry = slot.load(Ordering::Relaxed);
+        #[allow(clippy::cast_possible_truncation)]
+        (entry & MEMO_FILLED != 0 && (entry >> 16) & 0xff_ffff == key).then_some(entry as u16)
+    }
+
+    fn remember(&self, color: Color, index: u16) {
+        let (key, slot) = self.memo_slot(color);
+        slot.store(
+            MEMO_FILLED | key << 16 | u64::from(index),
+            Ordering::Relaxed,
+        );
+    }
+
+    /// The first of `candidates` that's closest to `input`, they have to be in palette order.
+    fn closest_of(&self, input: &Components, candidates: &mut dyn Iterator<Item = usize>) -> u16 {
+        // palettes are validated to have fewer than u16::MAX entries
+        #[allow(clippy::cast_possible_truncation)]
+        candidates
+            .min_by_key(|i| OrderedFloat((self.distance)(input, &self.components[*i])))
+            .map_or(0, |i| i as u16)
+    }
+
+    fn search(&self, color: Color) -> u16 {
+        let input = (self.conversion)(&color);
+        let closest =
+            |candidates: &mut dyn Iterator<Item = usize>| self.closest_of(&input, candidates);
+
+        let tree = |search: &dyn Fn(&mut Closest)| {
+            let mut closest = Closest {
+                distance: f64::INFINITY,
+                entry: 0,
+            };
+            search(&mut closest);
+            closest.entry
+        };
+
+        match &self.search {
+            Search::Scan => closest(&mut (0..self.components.len())),
+            Search::Grid(grid) => {
+                let cell = grid_cell(color);
+                let candidates =
+                    &grid.candidates[grid.offsets[cell] as usize..grid.offsets[cell + 1] as usize];
+                closest(&mut candidates.iter().map(|i| usize::from(*i)))
+            }
+            Search::KdTree(kd_tree) => tree(&|closest| {
+                kd_tree.search(&self.components, self.distance, &input, closest);
+            }),
+            Search::VpTree(vp_tree) => tree(&|closest| {
+                vp_tree.search(&self.components, self.distance, &input, closest);
+            }),
+        }
+    }
+}
+
+impl Grid {
+    fn new(
+        components: &[Components],
+        conversion: fn(&Color) -> Components,
+        distance: fn(&Components, &Components) -> f64,
+    ) -> Self {
+        let distance = |a: &Components, b: &Components| distance(a, b).sqrt();
+        let cells = 1usize << (3 * GRID_BITS);
+        let mut offsets = Vec::with_capacity(cells + 1);
+        let mut candidates = Vec::new();
+        offsets.push(0);
+
+        for cell in 0..cells {
+            // the colors of a cell are a box in RGB, which the conversion maps to a parallelepiped
+            // with the converted corners as its vertices, and their average as its center
+            #[allow(clippy::cast_possible_truncation)]
+            let low = [cell >> (2 * GRID_BITS), cell >> GRID_BITS, cell]
+                .map(|c| (c & ((1 << GRID_BITS) - 1)) as u8 * GRID_CELL);
+            let corners: Vec<Components> = (0..8)
+                .map(|corner| {
+                    let high = |axis: usize| u8::from(corner >> axis & 1 == 1) * (GRID_CELL - 1);
+                    conversion(&Color::new(
+                        low[0] + high(2),
+                        low[1] + high(1),
+                        low[2] + high(0),
+                        255,
+                    ))
+                })
+                .collect();
+            let center = corners.iter().fold(Components(0.0, 0.0, 0.0), |sum, c| {
+                Components(sum.0 + c.0 / 8.0, sum.1 + c.1 / 8.0, sum.2 + c.2 / 8.0)
+            });
+            // a norm is convex, so no color of the cell is further from the center than a corner
+            let radius = corners
+                .iter()
+                .map(|c| distance(&center, c))
+                .fold(0.0, f64::max);
+
+            // every color of the cell is within `nearest + radius` of some entry, so entries that
+            // are further than that from all of them can't be closest. the slack covers rounding
+            let distances: Vec<f64> = components.iter().map(|c| distance(&center, c)).collect();
+            let nearest = distances.iter().copied().fold(f64::INFINITY, f64::min);
+            let limit = nearest + 2.0 * radius + 1e-6 * (1.0 + nearest + radius);
+            #[allow(clippy::cast_possible_truncation)]
+            candidates.extend(
+                distances
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, d)| **d <= limit)
+                    .map(|(i, _)| i as u16),
+            );
+            #[allow(clippy::cast_possible_truncation)]
+            offsets.push(candidates.len() as u32);
+        }
+
+        Grid {
+            offsets,
+            candidates,
+        }
+    }
+}
+
+fn grid_cell(color: Color) -> usize {
+    let shift = 8 - GRID_BITS;
+    usize::from(color.red >> shift) << (2 * GRID_BITS)
+        | usize::from(color.green >> shift) << GRID_BITS
+        | usize::from(color.blue >> shift)
+}
+
+/// Whether `mode` compares squared euclidean distances of its components, which the k-d tree relies
+/// on.
+fn is_euclidean(mode: DistanceMode) -> bool {
+    matches!(
+        mode,
+        DistanceMode::KMeans
+            | DistanceMode::RGB
+            | DistanceMode::CIE76
+            | DistanceMode::XYZ
+            | DistanceMode::YCC
+            | DistanceMode::YIQ
+            | DistanceMode::YUV
+            | DistanceMode::OKLab
+    )
+}
+
+/// Whether the square root of the distance of `mode` is a metric, which the vp-tree relies on.
+/// Redmean, CIE94, CIEDE2000 and CMC break the triangle inequality, and the last two aren't even
+/// symmetric.
+fn is_metric(mode: DistanceMode) -> bool {
+    is_euclidean(mode) || mode == DistanceMode::LWRGB
+}
+
+/// Whether the distance of `mode` is a norm of the difference of linear functions of RGB, which
+/// the grid's bounds rely on.
+fn is_linear_norm(mode: DistanceMode) -> bool {
+    matches!(
+        mode,
+        DistanceMode::RGB
+            | DistanceMode::LWRGB
+            | DistanceMode::YCC
+            | DistanceMode::YIQ
+            | DistanceMode::YUV
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODES: [DistanceMode; 13] = [
+        DistanceMode::KMeans,
+        DistanceMode::RGB,
+        DistanceMode::LWRGB,
+        DistanceMode::Redmean,
+        DistanceMode::CIE76,
+        DistanceMode::CIE94,
+        DistanceMode::CIEDE2000,
+        DistanceMode::CMC,
+        DistanceMode::XYZ,
+        DistanceMode::YCC,
+        DistanceMode::YIQ,
+        DistanceMode::YUV,
+        DistanceMode::OKLab,
+    ];
+
+    fn random_palette(len: usize, seed: u64) -> Vec<Color> {
+        let mut state = seed;
+        let mut next = || {
+            state = state
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            (state >> 56) as u8
+        };
+        (0..len)
+            .map(|_| Color::new(next(), next(), next(), 255))
+            .collect()
+    }
+
+    /// Every sixth step along each channel, full of colors exactly between two entries.
+    fn lattice() -> Vec<Color> {
+        (0..216)
+            .map(|i| Color::new(i % 6 * 51, i / 6 % 6 * 51, i / 36 * 51, 255))
+            .collect()
+    }
+
+    /// Colors spread over the whole cube, along with everything in `palette`.
+    fn inputs(palette: &[Color], step: usize) -> Vec<Color> {
+        (0..1 << 24)
+            .step_by(step)
+            .map(|i: u32| {
+                let [_, r, g, b] = i.to_be_bytes();
+                Color::new(r, g, b, 255)
+            })
+            .chain(palette.iter().copied())
+            .chain(lattice())
+            .collect()
+    }
+
+    /// Checks every entry in order, keeping the first of the closest ones.
+    fn brute_force(components: &[Components], mode: DistanceMode, color: Color) -> u16 {
+        let input = color_conversion(mode)(&color);
+        let distance = color_distance(mode);
+        let mut closest = (f64::INFINITY, 0);
+        for (i, entry) in components.iter().enumerate() {
+            let d = distance(&input, entry);
+            if d < closest.0 {
+                closest = (d, u16::try_from(i).unwrap());
+            }
+        }
+        closest.1
+    }
+
+    fn lookup(palette: &[Color], mode: DistanceMode) -> ClosestLookup {
+        let conversion = color_conversion(mode);
+        ClosestLookup::new(palette.iter().map(conversion).collect(), mode)
+    }
+
+    /// Looks the inputs up in a batch, then one at a time out of the memo, and again with a fresh
+    /// memo.
+    fn assert_matches_brute_force(palette: &[Color], mode: DistanceMode, inputs: &[Color]) {
+        let lookup = lookup(palette, mode);
+        let mut indices = vec![0; inputs.len()];
+        lookup.find_all(inputs, &mut indices);
+        for (color, index) in inputs.iter().zip(indices) {
+            assert_eq!(
+                index,
+                brute_force(&lookup.components, mode, *color),
+                "{mode:?} with {} entries, {color:?} in a batch",
+                palette.len()
+            );
+        }
+
+        for lookup in [lookup, self::lookup(palette, mode)] {
+            for color in inputs {
+                assert_eq!(
+                    lookup.find(*color),
+                    brute_force(&lookup.components, mode, *color),
+                    "{mode:?} with {} entries, {color:?}",
+                    palette.len()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn matches_brute_force() {
+        let mut duplicates = random_palette(40, 3);
+        duplicates.extend_from_within(..20);
+        duplicates.rotate_left(7);
+        let palettes = [
+            random_palette(2, 1),
+            random_palette(15, 2),
+            random_palette(16, 4),
+            random_palette(100, 5),
+            duplicates,
+            lattice(),
+        ];
+
+        for mode in MODES {
+            for palette in &palettes {
+                assert_matches_brute_force(palette, mode, &inputs(palette, 16_411));
+            }
+        }
+    }
+
+    #[test]
+    fn trees_match_brute_force() {
+        let tree_modes = MODES.into_iter().filter(|mode| is_metric(*mode));
+        for (len, seed) in [
+            (TREE_MIN_PALETTE, 11),
+            (1000, 12),
+            (GRID_MAX_PALETTE + 904, 13),
+        ] {
+            // with every entry a second time, at the other end
+            let mut palette = random_palette(len / 2, seed);
+            palette.extend_from_within(..);
+            palette[len / 2..].reverse();
+            for mode in tree_modes.clone() {
+                let search = &lookup(&palette, mode).search;
+                if is_linear_norm(mode) && len <= GRID_MAX_PALETTE {
+                    assert!(matches!(search, Search::Grid(_)));
+                    continue;
+                }
+                assert_eq!(
+                    matches!(search, Search::KdTree(_)),
+                    is_euclidean(mode),
+                    "{mode:?}"
+                );
+                assert_eq!(
+                    matches!(search, Search::VpTree(_)),
+                    !is_euclidean(mode),
+                    "{mode:?}"
+                );
+                assert_matches_brute_force(
+                    &palette,
+                    mode,
+                    &inputs(&palette[..len.min(200)], 65_537),
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn picks_the_grid_for_linear_modes() {
+        for mode in MODES {
+            let search = lookup(&lattice(), mode).search;
+            assert_eq!(matches!(search, Search::Grid(_)), is_linear_norm(mode));
+        }
+        assert!(matches!(
+            lookup(&random_palette(8, 1), DistanceMode::RGB).search,
+            Search::Scan
+        ));
+    }
+
+    #[test]
+    fn ties_go_to_the_first_entry() {
+        // the same entries twice over, the second copy never gets picked
+        let mut palette = random_palette(24, 7);
+        palette.extend_from_within(..);
+        for mode in MODES {
+            let lookup = lookup(&palette, mode);
+            for color in inputs(&palette, 65_537) {
+                assert!(lookup.find(color) < 24, "{mode:?}, {color:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn transparent_colors_are_entry_zero() {
+        let palette = random_palette(20, 9);
+        for mode in MODES {
+            let transparent = Color {
+                alpha: 0,
+                ..palette[5]
+            };
+            assert_eq!(lookup(&palette, mode).find(transparent), 0);
+        }
+    }
+}
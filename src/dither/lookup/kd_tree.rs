@@ -0,0 +1,121 @@
+// a k-d tree over palette entries, for the modes that compare squared euclidean distances. a
+// subtree only gets skipped when the gap to its splitting plane alone, squared the same way the
+// distance squares it, is already further than the closest entry so far, so ties are still found
+
+use crate::Components;
+
+use super::Closest;
+
+/// The nodes in preorder. A node's left subtree follows it directly and ends where its right
+/// subtree starts, at `right`.
+pub(super) struct KdTree {
+    nodes: Vec<Node>,
+}
+
+struct Node {
+    entry: u16,
+    axis: u8,
+    right: u32,
+}
+
+impl KdTree {
+    pub(super) fn new(components: &[Components]) -> Self {
+        // palettes are validated to have fewer than u16::MAX entries
+        #[allow(clippy::cast_possible_truncation)]
+        let mut entries: Vec<u16> = (0..components.len() as u16).collect();
+        let mut nodes = Vec::with_capacity(entries.len());
+        build(components, &mut entries, &mut nodes);
+        KdTree { nodes }
+    }
+
+    pub(super) fn search(
+        &self,
+        components: &[Components],
+        distance: fn(&Components, &Components) -> f64,
+        input: &Components,
+        closest: &mut Closest,
+    ) {
+        self.search_range(components, distance, input, 0, self.nodes.len(), closest);
+    }
+
+    fn search_range(
+        &self,
+        components: &[Components],
+        distance: fn(&Components, &Components) -> f64,
+        input: &Components,
+        start: usize,
+        end: usize,
+        closest: &mut Closest,
+    ) {
+        let Some(node) = self.nodes.get(start).filter(|_| start < end) else {
+            return;
+        };
+        let entry = &components[usize::from(node.entry)];
+        closest.offer(distance(input, entry), node.entry);
+
+        let gap = axis(entry, node.axis) - axis(input, node.axis);
+        let left = (start + 1, node.right as usize);
+        let right = (node.right as usize, end);
+        // entries on the left are at or below this one on the axis, the right ones at or above
+        let (near, far) = if gap >= 0.0 {
+            (left, right)
+        } else {
+            (right, left)
+        };
+
+        self.search_range(components, distance, input, near.0, near.1, closest);
+        if gap * gap <= closest.distance {
+            self.search_range(components, distance, input, far.0, far.1, closest);
+        }
+    }
+}
+
+fn axis(c: &Components, axis: u8) -> f64 {
+    match axis {
+        0 => c.0,
+        1 => c.1,
+        _ => c.2,
+    }
+}
+
+/// Splits `entries` at the median of the axis they spread furthest along.
+fn build(components: &[Components], entries: &mut [u16], nodes: &mut Vec<Node>) {
+    if entries.is_empty() {
+        return;
+    }
+
+    let spread = |a: u8| {
+        let values = entries
+            .iter()
+            .map(|entry| axis(&components[usize::from(*entry)], a));
+        let (low, high) = values.fold((f64::INFINITY, f64::NEG_INFINITY), |(low, high), v| {
+            (low.min(v), high.max(v))
+        });
+        high - low
+    };
+    let split_axis = (0..3)
+        .max_by(|a, b| spread(*a).total_cmp(&spread(*b)))
+        .unwrap_or(0);
+    entries.sort_by(|a, b| {
+        axis(&components[usize::from(*a)], split_axis)
+            .total_cmp(&axis(&components[usize::from(*b)], split_axis))
+            .then(a.cmp(b))
+    });
+
+    let median = entries.len() / 2;
+    let (left, rest) = entries.split_at_mut(median);
+    let (entry, right) = rest.split_first_mut().expect("the median is in the slice");
+    let node = nodes.len();
+    nodes.push(Node {
+        entry: *entry,
+        axis: split_axis,
+        right: 0,
+    });
+    build(components, left, nodes);
+    // there are fewer nodes than palette entries
+    #[allow(clippy::cast_possible_truncation)]
+    {
+        nodes[node].right = nodes.len() as u32;
+    }
+    build(components, right, nodes);
+}
@@ -0,0 +1,122 @@
+// a vantage point tree over palette entries, for modes whose distance is a metric without being
+// euclidean in its components. the triangle inequality bounds how close a subtree can get, with a
+// little slack so rounding in the square roots never skips an entry that ties
+
+use crate::Components;
+
+use super::Closest;
+
+// far more than the rounding error of distances between colors, far less than any real difference
+const SLACK: f64 = 1e-9;
+
+/// The nodes in preorder. Entries of a node's inside subtree are at most `radius` from it, those of
+/// its outside subtree at least `radius`. The inside subtree follows the node directly and ends
+/// where the outside one starts, at `outside`.
+pub(super) struct VpTree {
+    nodes: Vec<Node>,
+}
+
+struct Node {
+    entry: u16,
+    radius: f64,
+    outside: u32,
+}
+
+impl VpTree {
+    pub(super) fn new(
+        components: &[Components],
+        distance: fn(&Components, &Components) -> f64,
+    ) -> Self {
+        // palettes are validated to have fewer than u16::MAX entries
+        #[allow(clippy::cast_possible_truncation)]
+        let mut entries: Vec<u16> = (0..components.len() as u16).collect();
+        let mut nodes = Vec::with_capacity(entries.len());
+        build(components, distance, &mut entries, &mut nodes);
+        VpTree { nodes }
+    }
+
+    pub(super) fn search(
+        &self,
+        components: &[Components],
+        distance: fn(&Components, &Components) -> f64,
+        input: &Components,
+        closest: &mut Closest,
+    ) {
+        self.search_range(components, distance, input, 0, self.nodes.len(), closest);
+    }
+
+    fn search_range(
+        &self,
+        components: &[Components],
+        distance: fn(&Components, &Components) -> f64,
+        input: &Components,
+        start: usize,
+        end: usize,
+        closest: &mut Closest,
+    ) {
+        let Some(node) = self.nodes.get(start).filter(|_| start < end) else {
+            return;
+        };
+        let distance2 = distance(input, &components[usize::from(node.entry)]);
+        closest.offer(distance2, node.entry);
+
+        // nothing in a subtree is nearer to the input than its gap
+        let d = distance2.sqrt();
+        let inside = (start + 1, node.outside as usize, d - node.radius);
+        let outside = (node.outside as usize, end, node.radius - d);
+        let order = if d <= node.radius {
+            [inside, outside]
+        } else {
+            [outside, inside]
+        };
+
+        for (start, end, gap) in order {
+            if gap <= closest.distance.sqrt() + SLACK {
+                self.search_range(components, distance, input, start, end, closest);
+            }
+        }
+    }
+}
+
+/// Takes the first entry as the vantage point and splits the rest at their median distance to it.
+fn build(
+    components: &[Components],
+    distance: fn(&Components, &Components) -> f64,
+    entries: &mut [u16],
+    nodes: &mut Vec<Node>,
+) {
+    let Some((vantage, rest)) = entries.split_first_mut() else {
+        return;
+    };
+    let vantage_components = &components[usize::from(*vantage)];
+    let mut distances: Vec<(f64, u16)> = rest
+        .iter()
+        .map(|entry| {
+            let d = distance(vantage_components, &components[usize::from(*entry)]);
+            (d.sqrt(), *entry)
+        })
+        .collect();
+    distances.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+    for (entry, (_, sorted)) in rest.iter_mut().zip(&distances) {
+        *entry = *sorted;
+    }
+
+    let median = distances.len().div_ceil(2);
+    let radius = median
+        .checked_sub(1)
+        .map_or(0.0, |last_inside| distances[last_inside].0);
+    let node = nodes.len();
+    nodes.push(Node {
+        entry: *vantage,
+        radius,
+        outside: 0,
+    });
+    let (inside, outside) = rest.split_at_mut(median);
+    build(components, distance, inside, nodes);
+    // there are fewer nodes than palette entries
+    #[allow(clippy::cast_possible_truncation)]
+    {
+        nodes[node].outside = nodes.len() as u32;
+    }
+    build(components, distance, outside, nodes);
+}
@@ -0,0 +1,636 @@
+// the deterministic palette quantizers. none of them iterate, so they're a lot cheaper than
+// k-means for previews, at the cost of some accuracy.
+#![allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss,
+    clippy::cast_precision_loss
+)]
+
+use std::{cmp::Reverse, collections::HashMap, ops::Range};
+
+use crate::Color;
+
+use wasm_bindgen::prelude::*;
+
+#[derive(Default, Clone, Copy)]
+#[wasm_bindgen]
+pub enum PaletteMethod {
+    #[default]
+    KMeans,
+    MedianCut,
+    Octree,
+    Wu,
+}
+
+/// Counts the distinct colors of the pixels in `data` with at least `alpha_threshold` alpha, sorted
+/// by color so the quantizers don't depend on hash order. Fully transparent pixels never count.
+pub(crate) fn color_histogram(data: &[Color], alpha_threshold: u8) -> Vec<([u8; 3], u32)> {
+    let mut counts: HashMap<[u8; 3], u32> = HashMap::new();
+    for color in data.iter().filter(|c| c.alpha >= alpha_threshold.max(1)) {
+        *counts.entry([color.red, color.green, color.blue]).or_default() += 1;
+    }
+
+    let mut histogram: Vec<_> = counts.into_iter().collect();
+    histogram.sort_unstable();
+    histogram
+}
+
+fn weighted_mean(colors: &[([u8; 3], u32)]) -> Color {
+    let mut sum = [0u64; 3];
+    let mut total = 0u64;
+    for (color, count) in colors {
+        for (s, c) in sum.iter_mut().zip(color) {
+            *s += u64::from(*c) * u64::from(*count);
+        }
+        total += u64::from(*count);
+    }
+
+    let channel = |s: u64| ((s + total / 2) / total.max(1)) as u8;
+    Color::new(channel(sum[0]), channel(sum[1]), channel(sum[2]), 255)
+}
+
+/// Median cut: keeps splitting the box with the widest channel range at the pixel median of that
+/// channel until there are `k` boxes, then averages each box.
+#[must_use]
+pub fn quantize_median_cut(data: &[Color], k: usize) -> Vec<Color> {
+    let mut histogram = color_histogram(data, 1);
+    if histogram.is_empty() || k == 0 {
+        return Vec::new();
+    }
+
+    // (range, widest channel, width of that channel, pixels in the box)
+    let measure = |histogram: &[([u8; 3], u32)], range: Range<usize>| {
+        let colors = &histogram[range.clone()];
+        let (channel, width) = (0..3)
+            .map(|channel| {
+                let min = colors.iter().map(|(c, _)| c[channel]).min().unwrap_or(0);
+                let max = colors.iter().map(|(c, _)| c[channel]).max().unwrap_or(0);
+                (channel, max - min)
+            })
+            .max_by_key(|(_, width)| *width)
+            .unwrap_or((0, 0));
+        let population: u64 = colors.iter().map(|(_, n)| u64::from(*n)).sum();
+        (range, channel, width, population)
+    };
+
+    let mut boxes = vec![measure(&histogram, 0..histogram.len())];
+    while boxes.len() < k {
+        let Some(index) = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, (range, ..))| range.len() > 1)
+            .max_by_key(|(_, (_, _, width, population))| (*width, *population))
+            .map(|(i, _)| i)
+        else {
+            break;
+        };
+
+        let (range, channel, _, population) = boxes[index].clone();
+        let colors = &mut histogram[range.clone()];
+        colors.sort_unstable_by_key(|(c, _)| (c[channel], *c));
+
+        let mut seen = 0;
+        let mut split = 1;
+        for (i, (_, n)) in colors.iter().enumerate() {
+            seen += u64::from(*n);
+            if seen > population / 2 {
+                split = (i + 1).clamp(1, colors.len() - 1);
+                break;
+            }
+        }
+
+        boxes[index] = measure(&histogram, range.start..range.start + split);
+        boxes.push(measure(&histogram, range.start + split..range.end));
+    }
+
+    boxes
+        .into_iter()
+        .map(|(range, ..)| weighted_mean(&histogram[range]))
+        .collect()
+}
+
+const OCTREE_DEPTH: usize = 8;
+
+#[derive(Default)]
+struct OctreeNode {
+    children: [Option<usize>; 8],
+    sum: [u64; 3],
+    count: u64,
+    /// Pixels anywhere below this node.
+    pixels: u64,
+    leaf: bool,
+}
+
+/// Octree quantization: colors are sorted into a tree by their bits, then the least populated
+/// deepest nodes get folded into their parents until at most `k` leaves remain. Folding takes all
+/// children at once, so this can end up with a few colors less than asked for.
+#[must_use]
+pub fn quantize_octree(data: &[Color], k: usize) -> Vec<Color> {
+    let histogram = color_histogram(data, 1);
+    if histogram.is_empty() || k == 0 {
+        return Vec::new();
+    }
+
+    let mut nodes = vec![OctreeNode::default()];
+    let mut reducible: Vec<Vec<usize>> = vec![Vec::new(); OCTREE_DEPTH];
+    reducible[0].push(0);
+    let mut leaves = 0;
+
+    for (color, count) in &histogram {
+        let mut node = 0;
+        nodes[0].pixels += u64::from(*count);
+        for level in 0..OCTREE_DEPTH {
+            let shift = 7 - level;
+            let child = (usize::from((color[0] >> shift) & 1) << 2)
+                | (usize::from((color[1] >> shift) & 1) << 1)
+                | usize::from((color[2] >> shift) & 1);
+
+            node = if let Some(next) = nodes[node].children[child] {
+                next
+            } else {
+                let next = nodes.len();
+                nodes.push(OctreeNode {
+                    leaf: level + 1 == OCTREE_DEPTH,
+                    ..Default::default()
+                });
+                if level + 1 == OCTREE_DEPTH {
+                    leaves += 1;
+                } else {
+                    reducible[level + 1].push(next);
+                }
+                nodes[node].children[child] = Some(next);
+                next
+            };
+            nodes[node].pixels += u64::from(*count);
+        }
+
+        let leaf = &mut nodes[node];
+        for (s, c) in leaf.sum.iter_mut().zip(color) {
+            *s += u64::from(*c) * u64::from(*count);
+        }
+        leaf.count += u64::from(*count);
+    }
+
+    // fold the nodes that represent the fewest pixels first, they cost the least accuracy
+    for level in &mut reducible {
+        level.sort_unstable_by_key(|n| Reverse((nodes[*n].pixels, *n)));
+    }
+
+    while leaves > k {
+        let Some(level) = (0..OCTREE_DEPTH).rev().find(|l| !reducible[*l].is_empty()) else {
+            break;
+        };
+
+        let Some(node) = reducible[level].pop() else {
+            break;
+        };
+
+        let children: Vec<usize> = nodes[node].children.iter().flatten().copied().collect();
+        for child in &children {
+            let (sum, count) = (nodes[*child].sum, nodes[*child].count);
+            for (s, c) in nodes[node].sum.iter_mut().zip(sum) {
+                *s += c;
+            }
+            nodes[node].count += count;
+            nodes[*child] = OctreeNode::default();
+        }
+        nodes[node].children = [None; 8];
+        nodes[node].leaf = true;
+        leaves = leaves + 1 - children.len();
+    }
+
+    nodes
+        .iter()
+        .filter(|n| n.leaf && n.count > 0)
+        .map(|n| {
+            let channel = |s: u64| ((s + n.count / 2) / n.count) as u8;
+            Color::new(channel(n.sum[0]), channel(n.sum[1]), channel(n.sum[2]), 255)
+        })
+        .collect()
+}
+
+// wu's quantizer works on a 5 bit per channel histogram, with an empty plane at index 0 so the
+// cumulative moments don't need bounds checks
+const WU_SIDE: usize = 33;
+
+fn wu_index(r: usize, g: usize, b: usize) -> usize {
+    (r * WU_SIDE + g) * WU_SIDE + b
+}
+
+#[derive(Clone, Copy, Default)]
+struct WuBox {
+    r0: usize,
+    r1: usize,
+    g0: usize,
+    g1: usize,
+    b0: usize,
+    b1: usize,
+}
+
+impl WuBox {
+    fn volume(&self) -> usize {
+        (self.r1 - self.r0) * (self.g1 - self.g0) * (self.b1 - self.b0)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum WuAxis {
+    Red,
+    Green,
+    Blue,
+}
+
+struct WuMoments {
+    weight: Vec<i64>,
+    red: Vec<i64>,
+    green: Vec<i64>,
+    blue: Vec<i64>,
+    squares: Vec<f64>,
+}
+
+impl WuMoments {
+    fn new(histogram: &[([u8; 3], u32)]) -> WuMoments {
+        let size = WU_SIDE * WU_SIDE * WU_SIDE;
+        let mut moments = WuMoments {
+            weight: vec![0; size],
+            red: vec![0; size],
+            green: vec![0; size],
+            blue: vec![0; size],
+            squares: vec![0.0; size],
+        };
+
+        for ([r, g, b], count) in histogram {
+            let index = wu_index(
+                usize::from(r >> 3) + 1,
+                usize::from(g >> 3) + 1,
+                usize::from(b >> 3) + 1,
+            );
+            let count = i64::from(*count);
+            let (r, g, b) = (i64::from(*r), i64::from(*g), i64::from(*b));
+            moments.weight[index] += count;
+            moments.red[index] += r * count;
+            moments.green[index] += g * count;
+            moments.blue[index] += b * count;
+            moments.squares[index] += ((r * r + g * g + b * b) * count) as f64;
+        }
+
+        // turn the histogram into cumulative moments, so any box can be summed in constant time
+        for r in 1..WU_SIDE {
+            let mut area = [0i64; WU_SIDE];
+            let mut area_r = [0i64; WU_SIDE];
+            let mut area_g = [0i64; WU_SIDE];
+            let mut area_b = [0i64; WU_SIDE];
+            let mut area_2 = [0f64; WU_SIDE];
+            for g in 1..WU_SIDE {
+                let mut line = 0;
+                let mut line_r = 0;
+                let mut line_g = 0;
+                let mut line_b = 0;
+                let mut line_2 = 0.0;
+                for b in 1..WU_SIDE {
+                    let index = wu_index(r, g, b);
+                    let previous = wu_index(r - 1, g, b);
+                    line += moments.weight[index];
+                    line_r += moments.red[index];
+                    line_g += moments.green[index];
+                    line_b += moments.blue[index];
+                    line_2 += moments.squares[index];
+                    area[b] += line;
+                    area_r[b] += line_r;
+                    area_g[b] += line_g;
+                    area_b[b] += line_b;
+                    area_2[b] += line_2;
+                    moments.weight[index] = moments.weight[previous] + area[b];
+                    moments.red[index] = moments.red[previous] + area_r[b];
+                    moments.green[index] = moments.green[previous] + area_g[b];
+                    moments.blue[index] = moments.blue[previous] + area_b[b];
+                    moments.squares[index] = moments.squares[previous] + area_2[b];
+                }
+            }
+        }
+
+        moments
+    }
+}
+
+fn wu_volume(cube: &WuBox, m: &[i64]) -> i64 {
+    m[wu_index(cube.r1, cube.g1, cube.b1)] - m[wu_index(cube.r1, cube.g1, cube.b0)]
+        - m[wu_index(cube.r1, cube.g0, cube.b1)]
+        + m[wu_index(cube.r1, cube.g0, cube.b0)]
+        - m[wu_index(cube.r0, cube.g1, cube.b1)]
+        + m[wu_index(cube.r0, cube.g1, cube.b0)]
+        + m[wu_index(cube.r0, cube.g0, cube.b1)]
+        - m[wu_index(cube.r0, cube.g0, cube.b0)]
+}
+
+fn wu_volume_f(cube: &WuBox, m: &[f64]) -> f64 {
+    m[wu_index(cube.r1, cube.g1, cube.b1)] - m[wu_index(cube.r1, cube.g1, cube.b0)]
+        - m[wu_index(cube.r1, cube.g0, cube.b1)]
+        + m[wu_index(cube.r1, cube.g0, cube.b0)]
+        - m[wu_index(cube.r0, cube.g1, cube.b1)]
+        + m[wu_index(cube.r0, cube.g1, cube.b0)]
+        + m[wu_index(cube.r0, cube.g0, cube.b1)]
+        - m[wu_index(cube.r0, cube.g0, cube.b0)]
+}
+
+/// The part of the box sum that doesn't depend on where along `axis` it gets cut.
+fn wu_bottom(cube: &WuBox, axis: WuAxis, m: &[i64]) -> i64 {
+    match axis {
+        WuAxis::Red => {
+            -m[wu_index(cube.r0, cube.g1, cube.b1)]
+                + m[wu_index(cube.r0, cube.g1, cube.b0)]
+                + m[wu_index(cube.r0, cube.g0, cube.b1)]
+                - m[wu_index(cube.r0, cube.g0, cube.b0)]
+        }
+        WuAxis::Green => {
+            -m[wu_index(cube.r1, cube.g0, cube.b1)]
+                + m[wu_index(cube.r1, cube.g0, cube.b0)]
+                + m[wu_index(cube.r0, cube.g0, cube.b1)]
+                - m[wu_index(cube.r0, cube.g0, cube.b0)]
+        }
+        WuAxis::Blue => {
+            -m[wu_index(cube.r1, cube.g1, cube.b0)]
+                + m[wu_index(cube.r1, cube.g0, cube.b0)]
+                + m[wu_index(cube.r0, cube.g1, cube.b0)]
+                - m[wu_index(cube.r0, cube.g0, cube.b0)]
+        }
+    }
+}
+
+/// The rest of the sum of the lower half when the box is cut at `position` along `axis`.
+fn wu_top(cube: &WuBox, axis: WuAxis, position: usize, m: &[i64]) -> i64 {
+    match axis {
+        WuAxis::Red => {
+            m[wu_index(position, cube.g1, cube.b1)] - m[wu_index(position, cube.g1, cube.b0)]
+                - m[wu_index(position, cube.g0, cube.b1)]
+                + m[wu_index(position, cube.g0, cube.b0)]
+        }
+        WuAxis::Green => {
+            m[wu_index(cube.r1, position, cube.b1)] - m[wu_index(cube.r1, position, cube.b0)]
+                - m[wu_index(cube.r0, position, cube.b1)]
+                + m[wu_index(cube.r0, position, cube.b0)]
+        }
+        WuAxis::Blue => {
+            m[wu_index(cube.r1, cube.g1, position)] - m[wu_index(cube.r1, cube.g0, position)]
+                - m[wu_index(cube.r0, cube.g1, position)]
+                + m[wu_index(cube.r0, cube.g0, position)]
+        }
+    }
+}
+
+fn wu_variance(cube: &WuBox, moments: &WuMoments) -> f64 {
+    let r = wu_volume(cube, &moments.red) as f64;
+    let g = wu_volume(cube, &moments.green) as f64;
+    let b = wu_volume(cube, &moments.blue) as f64;
+    let weight = wu_volume(cube, &moments.weight) as f64;
+    wu_volume_f(cube, &moments.squares) - (r * r + g * g + b * b) / weight
+}
+
+/// Finds the cut along `axis` that leaves the two halves with the least total variance. Returns
+/// the score of that cut (higher is better) and its position.
+fn wu_maximize(cube: &WuBox, axis: WuAxis, first: usize, last: usize, moments: &WuMoments) -> (f64, Option<usize>) {
+    let whole = [
+        wu_volume(cube, &moments.red),
+        wu_volume(cube, &moments.green),
+        wu_volume(cube, &moments.blue),
+        wu_volume(cube, &moments.weight),
+    ];
+    let base = [
+        wu_bottom(cube, axis, &moments.red),
+        wu_bottom(cube, axis, &moments.green),
+        wu_bottom(cube, axis, &moments.blue),
+        wu_bottom(cube, axis, &moments.weight),
+    ];
+
+    let score = |half: [i64; 4]| {
+        let [r, g, b, w] = half.map(|v| v as f64);
+        (r * r + g * g + b * b) / w
+    };
+
+    let mut max = 0.0;
+    let mut cut = None;
+    for position in first..last {
+        let half = [
+            base[0] + wu_top(cube, axis, position, &moments.red),
+            base[1] + wu_top(cube, axis, position, &moments.green),
+            base[2] + wu_top(cube, axis, position, &moments.blue),
+            base[3] + wu_top(cube, axis, position, &moments.weight),
+        ];
+        if half[3] == 0 {
+            continue;
+        }
+        let rest = [
+            whole[0] - half[0],
+            whole[1] - half[1],
+            whole[2] - half[2],
+            whole[3] - half[3],
+        ];
+        if rest[3] == 0 {
+            continue;
+        }
+
+        let temp = score(half) + score(rest);
+        if temp > max {
+            max = temp;
+            cut = Some(position);
+        }
+    }
+
+    (max, cut)
+}
+
+fn wu_cut(set1: &mut WuBox, moments: &WuMoments) -> Option<WuBox> {
+    let (max_r, cut_r) = wu_maximize(set1, WuAxis::Red, set1.r0 + 1, set1.r1, moments);
+    let (max_g, cut_g) = wu_maximize(set1, WuAxis::Green, set1.g0 + 1, set1.g1, moments);
+    let (max_b, cut_b) = wu_maximize(set1, WuAxis::Blue, set1.b0 + 1, set1.b1, moments);
+
+    let mut set2 = *set1;
+    if max_r >= max_g && max_r >= max_b {
+        let cut = cut_r?;
+        set2.r0 = cut;
+        set1.r1 = cut;
+    } else if max_g >= max_r && max_g >= max_b {
+        let cut = cut_g?;
+        set2.g0 = cut;
+        set1.g1 = cut;
+    } else {
+        let cut = cut_b?;
+        set2.b0 = cut;
+        set1.b1 = cut;
+    }
+
+    Some(set2)
+}
+
+/// Xiaolin Wu's quantizer: greedily cuts the color cube where it reduces the summed variance the
+/// most, using cumulative moment tables so every candidate cut is evaluated in constant time.
+#[must_use]
+pub fn quantize_wu(data: &[Color], k: usize) -> Vec<Color> {
+    let histogram = color_histogram(data, 1);
+    if histogram.is_empty() || k == 0 {
+        return Vec::new();
+    }
+
+    let moments = WuMoments::new(&histogram);
+    let mut cubes = vec![WuBox {
+        r0: 0,
+        r1: WU_SIDE - 1,
+        g0: 0,
+        g1: WU_SIDE - 1,
+        b0: 0,
+        b1: WU_SIDE - 1,
+    }];
+    let mut variances = vec![0.0];
+    let mut next = 0;
+
+    while cubes.len() < k {
+        if let Some(cube) = wu_cut(&mut cubes[next], &moments) {
+            variances[next] = if cubes[next].volume() > 1 {
+                wu_variance(&cubes[next], &moments)
+            } else {
+                0.0
+            };
+            variances.push(if cube.volume() > 1 {
+                wu_variance(&cube, &moments)
+            } else {
+                0.0
+            });
+            cubes.push(cube);
+        } else {
+            variances[next] = 0.0;
+        }
+
+        let Some((best, variance)) = variances
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(b.1).then(b.0.cmp(&a.0)))
+        else {
+            break;
+        };
+        if *variance <= 0.0 {
+            break;
+        }
+        next = best;
+    }
+
+    cubes
+        .iter()
+        .filter_map(|cube| {
+            let weight = wu_volume(cube, &moments.weight);
+            (weight > 0).then(|| {
+                let channel = |m: &[i64]| ((wu_volume(cube, m) + weight / 2) / weight) as u8;
+                Color::new(
+                    channel(&moments.red),
+                    channel(&moments.green),
+                    channel(&moments.blue),
+                    255,
+                )
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Quantizer = fn(&[Color], usize) -> Vec<Color>;
+
+    const QUANTIZERS: [(&str, Quantizer); 3] = [
+        ("median cut", quantize_median_cut),
+        ("octree", quantize_octree),
+        ("wu", quantize_wu),
+    ];
+
+    fn random_image(len: usize, seed: u64) -> Vec<Color> {
+        let mut state = seed;
+        let mut next = || {
+            state = state
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            (state >> 56) as u8
+        };
+        (0..len)
+            .map(|_| Color::new(next(), next(), next(), 255))
+            .collect()
+    }
+
+    fn sorted(colors: &[Color]) -> Vec<[u8; 4]> {
+        let mut colors: Vec<_> = colors
+            .iter()
+            .map(|c| [c.red, c.green, c.blue, c.alpha])
+            .collect();
+        colors.sort_unstable();
+        colors.dedup();
+        colors
+    }
+
+    /// Colors far enough apart that they never share one of Wu's histogram cells.
+    fn few_colors() -> Vec<Color> {
+        let colors = [
+            [0, 0, 0],
+            [255, 255, 255],
+            [200, 30, 40],
+            [40, 200, 90],
+            [20, 60, 220],
+            [250, 220, 30],
+            [128, 128, 128],
+        ];
+        (0..200)
+            .map(|i| {
+                let [r, g, b] = colors[i * i % colors.len()];
+                Color::new(r, g, b, 255)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn never_more_colors_than_asked_for() {
+        let image = random_image(4096, 1);
+        for (name, quantize) in QUANTIZERS {
+            assert!(quantize(&image, 0).is_empty(), "{name}");
+            for k in [1, 2, 5, 16, 64, 255] {
+                let palette = quantize(&image, k);
+                assert!(!palette.is_empty() && palette.len() <= k, "{name} {k}");
+            }
+        }
+    }
+
+    #[test]
+    fn few_colors_come_back_exactly() {
+        let image = few_colors();
+        for (name, quantize) in QUANTIZERS {
+            for k in [7, 8, 32] {
+                assert_eq!(sorted(&quantize(&image, k)), sorted(&image), "{name} {k}");
+            }
+        }
+    }
+
+    #[test]
+    fn output_only_depends_on_the_colors() {
+        let image = random_image(4096, 2);
+        let mut reversed = image.clone();
+        reversed.reverse();
+        for (name, quantize) in QUANTIZERS {
+            let palette = quantize(&image, 16);
+            assert_eq!(quantize(&image, 16), palette, "{name}");
+            assert_eq!(quantize(&reversed, 16), palette, "{name}");
+        }
+    }
+
+    #[test]
+    fn transparent_pixels_are_ignored() {
+        let image = few_colors();
+        let mut with_transparent = image.clone();
+        with_transparent.extend(random_image(500, 3).iter().map(|c| Color { alpha: 0, ..*c }));
+        for (name, quantize) in QUANTIZERS {
+            for k in [3, 7] {
+                assert_eq!(quantize(&with_transparent, k), quantize(&image, k), "{name} {k}");
+            }
+        }
+        let transparent = vec![Color::new(255, 0, 0, 0); 10];
+        for (name, quantize) in QUANTIZERS {
+            assert!(quantize(&transparent, 4).is_empty(), "{name}");
+        }
+    }
+}
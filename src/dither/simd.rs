@@ -0,0 +1,280 @@
+// batched color conversions and distances, a lane per color in f32. the powers in the sRGB transfer
+// and the cube root are polynomial fits, so the results are only within the tolerances below of
+// the scalar f64 conversions. the closest palette search uses them to rule entries out, and only
+// does the exact math when more than one entry is still in the running
+
+use std::simd::{
+    cmp::{SimdPartialEq, SimdPartialOrd},
+    num::{SimdFloat, SimdInt, SimdUint},
+    Simd,
+};
+
+use crate::{Color, Components};
+
+use super::DistanceMode;
+
+const LANES: usize = 8;
+type F = Simd<f32, LANES>;
+
+// how far a distance between a batched conversion and a palette entry in f32 can be from the exact
+// one. over every sRGB color, the conversions are at most 1.4e-6, 5.8e-7 and 2.8e-4 from the scalar
+// ones, which the tests check, and these leave room for rounding on top
+const TOLERANCE_OKLAB: f32 = 2e-5;
+const TOLERANCE_XYZ: f32 = 2e-5;
+const TOLERANCE_LAB: f32 = 2e-3;
+
+// chebyshev fits of `m^0.4` and `cbrt(m)` over 1..2, as coefficients of powers of `2m - 3`.
+// both are within 4e-7 of the real thing, relative to it
+const POW_0_4: [f32; 7] = [
+    1.176_079,
+    0.156_811_78,
+    -0.015_681_398,
+    0.002_777_89,
+    -0.000_601_286_34,
+    0.000_164_092_29,
+    -0.000_042_329_55,
+];
+const CBRT: [f32; 7] = [
+    1.144_714_2,
+    0.127_191_73,
+    -0.014_132_625,
+    0.002_607_143_7,
+    -0.000_578_798,
+    0.000_161_459_36,
+    -0.000_042_239_59,
+];
+
+/// Colors in a color space, a plane per component so they load straight into lanes.
+#[derive(Default)]
+pub(crate) struct Planes {
+    planes: [Vec<f32>; 3],
+}
+
+impl Planes {
+    /// Palette entries for [`Planes::distances2`]. The planes are padded to a whole number of
+    /// batches with entries that are infinitely far from everything.
+    #[allow(clippy::cast_possible_truncation)]
+    pub(crate) fn palette(components: &[Components]) -> Self {
+        let len = components.len().next_multiple_of(LANES);
+        let plane = |component: fn(&Components) -> f64| {
+            let mut plane: Vec<f32> = components.iter().map(|c| component(c) as f32).collect();
+            plane.resize(len, f32::INFINITY);
+            plane
+        };
+
+        Planes {
+            planes: [plane(|c| c.0), plane(|c| c.1), plane(|c| c.2)],
+        }
+    }
+
+    pub(crate) fn get(&self, i: usize) -> [f32; 3] {
+        self.planes.each_ref().map(|plane| plane[i])
+    }
+
+    /// The squared euclidean distances from `input` to every entry, padding included.
+    pub(crate) fn distances2(&self, input: [f32; 3], distances: &mut Vec<f32>) {
+        let [c0, c1, c2] = &self.planes;
+        let input = input.map(F::splat);
+        distances.clear();
+        for ((c0, c1), c2) in c0
+            .chunks_exact(LANES)
+            .zip(c1.chunks_exact(LANES))
+            .zip(c2.chunks_exact(LANES))
+        {
+            let d0 = F::from_slice(c0) - input[0];
+            let d1 = F::from_slice(c1) - input[1];
+            let d2 = F::from_slice(c2) - input[2];
+            distances.extend_from_slice((d0 * d0 + d1 * d1 + d2 * d2).as_array());
+        }
+    }
+}
+
+/// Converts a batch of colors into a color space.
+pub(crate) type BatchConversion = fn(&[Color]) -> Planes;
+
+/// The batched version of the conversion `mode` compares colors in, with how far off distances
+/// from it can be, for modes that compare squared euclidean distances.
+pub(crate) fn batch_conversion(mode: DistanceMode) -> Option<(BatchConversion, f32)> {
+    match mode {
+        DistanceMode::KMeans | DistanceMode::OKLab => Some((colors_to_oklab, TOLERANCE_OKLAB)),
+        DistanceMode::XYZ => Some((colors_to_xyz, TOLERANCE_XYZ)),
+        DistanceMode::CIE76 => Some((colors_to_lab, TOLERANCE_LAB)),
+        _ => None,
+    }
+}
+
+fn batch(colors: &[Color], convert: impl Fn([F; 3]) -> [F; 3]) -> Planes {
+    let mut planes = Planes {
+        planes: [(); 3].map(|()| Vec::with_capacity(colors.len().next_multiple_of(LANES))),
+    };
+    for chunk in colors.chunks(LANES) {
+        let mut rgb = [[0.0; LANES]; 3];
+        for (lane, c) in chunk.iter().enumerate() {
+            rgb[0][lane] = f32::from(c.red);
+            rgb[1][lane] = f32::from(c.green);
+            rgb[2][lane] = f32::from(c.blue);
+        }
+        let converted = convert(rgb.map(|c| F::from_array(c) / F::splat(255.0)));
+        for (plane, c) in planes.planes.iter_mut().zip(converted) {
+            plane.extend_from_slice(&c.as_array()[..chunk.len()]);
+        }
+    }
+    planes
+}
+
+fn colors_to_oklab(colors: &[Color]) -> Planes {
+    batch(colors, |rgb| {
+        let [r, g, b] = rgb.map(srgb_to_linear);
+
+        let l =
+            F::splat(0.412_221_46) * r + F::splat(0.536_332_55) * g + F::splat(0.051_445_995) * b;
+        let m = F::splat(0.211_903_5) * r + F::splat(0.680_699_5) * g + F::splat(0.107_396_96) * b;
+        let s = F::splat(0.088_302_46) * r + F::splat(0.281_718_85) * g + F::splat(0.629_978_7) * b;
+
+        let [l, m, s] = [l, m, s].map(cbrt);
+
+        [
+            F::splat(0.210_454_26) * l + F::splat(0.793_617_8) * m - F::splat(0.004_072_047) * s,
+            F::splat(1.977_998_5) * l - F::splat(2.428_592_2) * m + F::splat(0.450_593_7) * s,
+            F::splat(0.025_904_037) * l + F::splat(0.782_771_77) * m - F::splat(0.808_675_77) * s,
+        ]
+    })
+}
+
+fn xyz(rgb: [F; 3]) -> [F; 3] {
+    let [r, g, b] = rgb.map(|c| srgb_to_linear(c) * F::splat(100.0));
+
+    [
+        (r * F::splat(0.4124) + g * F::splat(0.3576) + b * F::splat(0.1805)) / F::splat(95.05),
+        (r * F::splat(0.2126) + g * F::splat(0.7152) + b * F::splat(0.0722)) / F::splat(100.0),
+        (r * F::splat(0.0193) + g * F::splat(0.1192) + b * F::splat(0.9504)) / F::splat(108.89),
+    ]
+}
+
+fn colors_to_xyz(colors: &[Color]) -> Planes {
+    batch(colors, xyz)
+}
+
+fn colors_to_lab(colors: &[Color]) -> Planes {
+    batch(colors, |rgb| {
+        let [x, y, z] = xyz(rgb).map(|c| {
+            c.simd_gt(F::splat(0.008_856))
+                .select(cbrt(c), F::splat(7.787) * c + F::splat(16.0 / 116.0))
+        });
+
+        [
+            F::splat(116.0) * y - F::splat(16.0),
+            F::splat(500.0) * (x - y),
+            F::splat(200.0) * (y - z),
+        ]
+    })
+}
+
+fn horner(coefficients: &[f32], x: F) -> F {
+    coefficients
+        .iter()
+        .rev()
+        .fold(F::splat(0.0), |sum, c| sum * x + F::splat(*c))
+}
+
+/// Splits positive normal floats into `m * 2^e` with `m` in 1..2, returning `2m - 3` for the fits
+/// and `e`.
+#[allow(clippy::cast_possible_wrap)]
+fn split(x: F) -> (F, Simd<i32, LANES>) {
+    let bits = x.to_bits();
+    let exponent = (bits >> Simd::splat(23)).cast::<i32>() - Simd::splat(127);
+    let m = F::from_bits((bits & Simd::splat(0x007f_ffff)) | Simd::splat(0x3f80_0000));
+    (m * F::splat(2.0) - F::splat(3.0), exponent)
+}
+
+/// Decodes sRGB values in 0..1.
+fn srgb_to_linear(c: F) -> F {
+    // `t^2.4` is `t^2 * m^0.4 * 2^(0.4e)`, where past the linear segment `e` is -4 to 0
+    let t = (c + F::splat(0.055)) / F::splat(1.055);
+    let (m, e) = split(t);
+    let pow_2_e = [0.329_877, 0.435_275_3, 0.574_349_2, 0.757_858_3]
+        .iter()
+        .zip(-4..)
+        .fold(F::splat(1.0), |pow, (value, exponent)| {
+            e.simd_eq(Simd::splat(exponent))
+                .select(F::splat(*value), pow)
+        });
+    let curve = t * t * horner(&POW_0_4, m) * pow_2_e;
+
+    c.simd_ge(F::splat(0.040_45))
+        .select(curve, c / F::splat(12.92))
+}
+
+/// The cube root of values that aren't negative. With `x` split into `m * 2^(3q + r)`, the root
+/// is `cbrt(m) * cbrt(2^r) * 2^q`.
+#[allow(clippy::cast_sign_loss)]
+fn cbrt(x: F) -> F {
+    let (m, exponent) = split(x);
+    // the exponent plus 150 is positive for every float, so dividing rounds down
+    let exponent = exponent + Simd::splat(150);
+    let q = exponent / Simd::splat(3) - Simd::splat(50);
+    let r = exponent % Simd::splat(3);
+
+    let root = horner(&CBRT, m);
+    let cbrt_2r = r.simd_eq(Simd::splat(0)).select(
+        F::splat(1.0),
+        r.simd_eq(Simd::splat(1))
+            .select(F::splat(1.259_921), F::splat(1.587_401)),
+    );
+    let power = F::from_bits(((q + Simd::splat(127)) << Simd::splat(23)).cast::<u32>());
+
+    // subnormals don't split like that, but their roots are close enough to nothing
+    x.simd_lt(F::splat(f32::MIN_POSITIVE))
+        .select(F::splat(0.0), root * cbrt_2r * power)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dither::{color_to_lab, color_to_oklab, color_to_xyz};
+
+    type Check = (BatchConversion, fn(&Color) -> Components, f64, f32);
+
+    // the conversions with the error the module comment gives for them, and their tolerance
+    const CHECKS: [Check; 3] = [
+        (colors_to_oklab, color_to_oklab, 1.4e-6, TOLERANCE_OKLAB),
+        (colors_to_xyz, color_to_xyz, 5.8e-7, TOLERANCE_XYZ),
+        (colors_to_lab, color_to_lab, 2.8e-4, TOLERANCE_LAB),
+    ];
+
+    /// Checks that no color with a red of every `step`th value converts further than the
+    /// documented error from the scalar conversion, and that the tolerance leaves room on top.
+    fn assert_within_tolerance(step: usize) {
+        for (batch, scalar, max_error, tolerance) in CHECKS {
+            assert!(max_error * 4.0 < f64::from(tolerance));
+            // a slice of the cube at a time
+            for red in (0..=255).step_by(step) {
+                let colors: Vec<Color> = (0..1 << 16)
+                    .map(|i: u32| {
+                        let [_, _, g, b] = i.to_be_bytes();
+                        Color::new(red, g, b, 255)
+                    })
+                    .collect();
+                let converted = batch(&colors);
+                for (i, color) in colors.iter().enumerate() {
+                    let [c0, c1, c2] = converted.get(i).map(f64::from);
+                    let Components(s0, s1, s2) = scalar(color);
+                    let error = ((c0 - s0).powi(2) + (c1 - s1).powi(2) + (c2 - s2).powi(2)).sqrt();
+                    assert!(error <= max_error, "{color:?} is {error:e} off");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn conversions_are_within_tolerance() {
+        assert_within_tolerance(51);
+    }
+
+    /// Every sRGB color, which takes minutes without optimizations.
+    #[test]
+    #[ignore]
+    fn every_color_is_within_tolerance() {
+        assert_within_tolerance(1);
+    }
+}
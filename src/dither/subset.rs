@@ -0,0 +1,116 @@
+use crate::{Color, Components, DistanceMode};
+
+use super::{color_conversion, color_distance, quantize::color_histogram};
+
+/// Picks the `budget` entries of `palette` that reproduce the visible pixels of `data` with the
+/// least total error under `distance_mode`, and returns their indices in palette order. At least
+/// one entry is always kept.
+///
+/// This removes entries greedily: every round drops the entry whose pixels are cheapest to move
+/// to their second closest entry.
+#[must_use]
+pub fn select_palette_subset(
+    data: &[Color],
+    palette: &[Color],
+    budget: usize,
+    distance_mode: DistanceMode,
+) -> Vec<usize> {
+    let conversion = color_conversion(distance_mode);
+    let palette_components: Vec<Components> = palette.iter().map(conversion).collect();
+    select_palette_subset_with(data, &palette_components, budget, distance_mode, 1)
+}
+
+/// `select_palette_subset` for a palette that's already in the color space of `distance_mode`,
+/// only looking at pixels with at least `alpha_threshold` alpha, the ones that don't get dithered
+/// to transparent.
+pub(crate) fn select_palette_subset_with(
+    data: &[Color],
+    palette_components: &[Components],
+    budget: usize,
+    distance_mode: DistanceMode,
+    alpha_threshold: u8,
+) -> Vec<usize> {
+    let mut kept: Vec<usize> = (0..palette_components.len()).collect();
+    if budget >= palette_components.len() {
+        return kept;
+    }
+
+    let conversion = color_conversion(distance_mode);
+    let distance = color_distance(distance_mode);
+
+    let histogram = color_histogram(data, alpha_threshold);
+    let distances: Vec<Vec<f64>> = histogram
+        .iter()
+        .map(|([r, g, b], _)| {
+            let color = conversion(&Color::new(*r, *g, *b, 255));
+            palette_components
+                .iter()
+                .map(|p| distance(&color, p).max(0.0))
+                .collect()
+        })
+        .collect();
+
+    // the closest and second closest kept entry for every distinct color
+    let closest_two = |row: &[f64], kept: &[usize]| {
+        let mut best = (usize::MAX, f64::INFINITY);
+        let mut second = (usize::MAX, f64::INFINITY);
+        for &i in kept {
+            if row[i] < best.1 {
+                second = best;
+                best = (i, row[i]);
+            } else if row[i] < second.1 {
+                second = (i, row[i]);
+            }
+        }
+        (best, second)
+    };
+    let mut nearest: Vec<_> = distances.iter().map(|row| closest_two(row, &kept)).collect();
+
+    while kept.len() > budget.max(1) {
+        let mut cost = vec![0.0; palette_components.len()];
+        for ((best, second), (_, count)) in nearest.iter().zip(&histogram) {
+            cost[best.0] += (second.1 - best.1) * f64::from(*count);
+        }
+
+        let Some(position) = (0..kept.len()).min_by(|a, b| cost[kept[*a]].total_cmp(&cost[kept[*b]])) else {
+            break;
+        };
+        let removed = kept.remove(position);
+
+        for (row, entry) in distances.iter().zip(nearest.iter_mut()) {
+            if entry.0 .0 == removed || entry.1 .0 == removed {
+                *entry = closest_two(row, &kept);
+            }
+        }
+    }
+
+    kept
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pixels_below_the_alpha_threshold_dont_count() {
+        let palette = [
+            Color::new(255, 0, 0, 255),
+            Color::new(0, 255, 0, 255),
+            Color::new(0, 0, 255, 255),
+        ];
+        let components: Vec<Components> = palette
+            .iter()
+            .map(color_conversion(DistanceMode::RGB))
+            .collect();
+        // mostly faint red, which the dither makes transparent with a threshold above its alpha
+        let mut data = vec![Color::new(255, 0, 0, 100); 50];
+        data.extend([Color::new(0, 255, 0, 255); 3]);
+        data.extend([Color::new(0, 0, 255, 255); 2]);
+
+        let subset = |alpha_threshold| {
+            select_palette_subset_with(&data, &components, 2, DistanceMode::RGB, alpha_threshold)
+        };
+        assert_eq!(subset(1), [0, 1]);
+        assert_eq!(subset(128), [1, 2]);
+    }
+}
@@ -0,0 +1,117 @@
+#![allow(clippy::module_name_repetitions)]
+
+// writing results as PNG. dithered images only use palette colors, so they go out as paletted
+// PNGs at the smallest bit depth that holds the palette, which is a lot smaller than RGBA
+
+use std::io::{BufWriter, Cursor};
+
+use image::{
+    error::{EncodingError, LimitError, LimitErrorKind},
+    write_buffer_with_format, ColorType, ImageError, ImageFormat,
+};
+use png::BitDepth;
+
+use crate::{Error, IndexedSprite};
+
+fn encode_error(
+    e: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+) -> Error {
+    Error::Encode(ImageError::Encoding(EncodingError::new(
+        ImageFormat::Png.into(),
+        e,
+    )))
+}
+
+fn dimensions(sprite: &IndexedSprite) -> Result<(u32, u32), Error> {
+    match (u32::try_from(sprite.width), u32::try_from(sprite.height)) {
+        (Ok(width), Ok(height)) => Ok((width, height)),
+        _ => Err(Error::Encode(ImageError::Limits(LimitError::from_kind(
+            LimitErrorKind::DimensionError,
+        )))),
+    }
+}
+
+/// Encodes `sprite` as a paletted PNG when its palette has 256 entries or fewer, and as RGBA
+/// otherwise.
+pub(crate) fn encode_png(sprite: &IndexedSprite) -> Result<Vec<u8>, Error> {
+    if sprite.palette.len() <= 256 {
+        return encode_indexed_png(sprite);
+    }
+
+    let (width, height) = dimensions(sprite)?;
+    let data = sprite.to_sprite().to_rgba();
+
+    let mut output_image = Cursor::new(Vec::new());
+    write_buffer_with_format(
+        &mut BufWriter::new(&mut output_image),
+        &data,
+        width,
+        height,
+        ColorType::Rgba8,
+        ImageFormat::Png,
+    )
+    .map_err(Error::Encode)?;
+    Ok(output_image.into_inner())
+}
+
+/// Encodes `sprite` as a 1, 2, 4 or 8 bit paletted PNG, with a `tRNS` chunk if any palette entry
+/// isn't opaque.
+///
+/// # Errors
+///
+/// This function will return an `Encode` error if the palette is empty or has more than 256
+/// entries, or the sprite is too large for a PNG.
+pub fn encode_indexed_png(sprite: &IndexedSprite) -> Result<Vec<u8>, Error> {
+    if sprite.palette.is_empty() || sprite.palette.len() > 256 {
+        return Err(encode_error(format!(
+            "a paletted PNG holds 1 to 256 colors, not {}",
+            sprite.palette.len()
+        )));
+    }
+
+    let (width, height) = dimensions(sprite)?;
+    let (depth, bits) = match sprite.palette.len() {
+        0..=2 => (BitDepth::One, 1),
+        3..=4 => (BitDepth::Two, 2),
+        5..=16 => (BitDepth::Four, 4),
+        _ => (BitDepth::Eight, 8),
+    };
+
+    let rgb: Vec<u8> = sprite
+        .palette
+        .iter()
+        .flat_map(|c| [c.red, c.green, c.blue])
+        .collect();
+    // entries past the last translucent one are opaque without being listed
+    let alpha: Vec<u8> = sprite.palette.iter().map(|c| c.alpha).collect();
+    let trns_len = alpha.iter().rposition(|&a| a != 255).map_or(0, |i| i + 1);
+
+    // rows start on a byte boundary, pixels are packed from the high bits down
+    let pixels_per_byte = 8 / bits;
+    let row_bytes = sprite.width.div_ceil(pixels_per_byte);
+    let mut data = vec![0u8; row_bytes * sprite.height];
+    if sprite.width > 0 {
+        for (y, row) in data.chunks_exact_mut(row_bytes).enumerate() {
+            for x in 0..sprite.width {
+                let index = sprite.indices.get(y * sprite.width + x).unwrap_or(0);
+                // anything that needs 16 bits is past the palette anyway
+                let index = u8::try_from(index).unwrap_or(0);
+                let shift = 8 - bits * (x % pixels_per_byte + 1);
+                row[x / pixels_per_byte] |= index << shift;
+            }
+        }
+    }
+
+    let mut output_image = Vec::new();
+    let mut encoder = png::Encoder::new(&mut output_image, width, height);
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(depth);
+    encoder.set_palette(rgb);
+    if trns_len > 0 {
+        encoder.set_trns(&alpha[..trns_len]);
+    }
+    let mut writer = encoder.write_header().map_err(encode_error)?;
+    writer.write_image_data(&data).map_err(encode_error)?;
+    writer.finish().map_err(encode_error)?;
+    Ok(output_image)
+}
@@ -0,0 +1,89 @@
+#![allow(clippy::module_name_repetitions)]
+
+use std::fmt;
+
+use js_sys::Reflect;
+use wasm_bindgen::JsValue;
+
+use crate::{ColorParseError, PaletteError};
+
+#[derive(Debug)]
+pub enum Error {
+    /// The input isn't an image the decoder understands.
+    ImageDecode(image::ImageError),
+    /// Palette entry `index` isn't a color.
+    PaletteParse { index: usize, text: String },
+    /// A palette file couldn't be read or written.
+    PaletteFile(PaletteError),
+    /// Options that can't be honored, like an outline index past the end of the palette.
+    InvalidOptions(String),
+    /// The result couldn't be packed into a PNG.
+    Encode(image::ImageError),
+}
+
+impl Error {
+    /// A stable name for the variant, handed to JS as the `kind` of the thrown error.
+    #[must_use]
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::ImageDecode(_) => "image_decode",
+            Error::PaletteParse { .. } => "palette_parse",
+            Error::PaletteFile(_) => "palette_file",
+            Error::InvalidOptions(_) => "invalid_options",
+            Error::Encode(_) => "encode",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::ImageDecode(e) => write!(f, "can't decode image: {e}"),
+            Error::PaletteParse { index, text } => {
+                write!(f, "invalid color {text:?} at palette index {index}")
+            }
+            Error::PaletteFile(e) => write!(f, "{e}"),
+            Error::InvalidOptions(reason) => write!(f, "invalid options: {reason}"),
+            Error::Encode(e) => write!(f, "can't encode image: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::ImageDecode(e) | Error::Encode(e) => Some(e),
+            Error::PaletteFile(e) => Some(e),
+            Error::PaletteParse { .. } | Error::InvalidOptions(_) => None,
+        }
+    }
+}
+
+impl From<ColorParseError> for Error {
+    fn from(e: ColorParseError) -> Self {
+        Error::PaletteParse {
+            index: e.index,
+            text: e.text,
+        }
+    }
+}
+
+impl From<PaletteError> for Error {
+    fn from(e: PaletteError) -> Self {
+        Error::PaletteFile(e)
+    }
+}
+
+/// Becomes a JS `Error` with the message, a `kind` from `Error::kind`, and for palette parse errors
+/// the `index` of the entry.
+impl From<Error> for JsValue {
+    fn from(error: Error) -> Self {
+        let js_error = js_sys::Error::new(&error.to_string());
+        // setting a property on a plain object can't fail
+        let _ = Reflect::set(&js_error, &"kind".into(), &error.kind().into());
+        if let Error::PaletteParse { index, .. } = error {
+            let _ = Reflect::set(&js_error, &"index".into(), &index.into());
+        }
+        js_error.into()
+    }
+}
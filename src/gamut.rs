@@ -0,0 +1,379 @@
+#![allow(clippy::module_name_repetitions)]
+
+// gamut mapping happens in OKLab, on the convex hull of the palette. anything outside of the hull
+// can't be mixed out of palette colors by the ordered dithers, so it gets pulled inwards first.
+
+use crate::{
+    dither::{color_to_oklab, oklab_to_color},
+    Color, Components,
+};
+
+use wasm_bindgen::prelude::*;
+
+const EPSILON: f64 = 1e-9;
+// fraction of the way to the hull boundary that soft compression leaves untouched
+const COMPRESS_KNEE: f64 = 0.8;
+
+/// How colors the palette can't mix get pulled into its gamut before dithering.
+///
+/// The gamut is the convex hull of the palette in OKLab, so palettes that don't span a volume
+/// (fewer than four distinct colors, or all of them on one plane, like grayscale palettes) have
+/// none, and every mode leaves colors as they are for them.
+#[derive(Default, PartialEq, Clone, Copy)]
+#[wasm_bindgen]
+pub enum GamutMapMode {
+    #[default]
+    None,
+    /// Pulls colors outside of the palette's hull straight towards its center.
+    Clip,
+    /// Rescales lightness into the palette's range and squeezes chroma with a soft knee, so
+    /// saturated gradients keep some of their detail.
+    Compress,
+    /// Clamps lightness into the palette's range and only takes away chroma.
+    PreserveLightness,
+}
+
+type Vec3 = [f64; 3];
+
+fn sub(a: Vec3, b: Vec3) -> Vec3 {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn add_scaled(a: Vec3, b: Vec3, t: f64) -> Vec3 {
+    [a[0] + b[0] * t, a[1] + b[1] * t, a[2] + b[2] * t]
+}
+
+fn dot(a: Vec3, b: Vec3) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: Vec3, b: Vec3) -> Vec3 {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+fn length(a: Vec3) -> f64 {
+    dot(a, a).sqrt()
+}
+
+/// A face of the hull, everything with `dot(normal, p) <= offset` is on the inside.
+struct Plane {
+    normal: Vec3,
+    offset: f64,
+}
+
+struct Hull {
+    planes: Vec<Plane>,
+    min_l: f64,
+    max_l: f64,
+    center: Vec3,
+}
+
+impl Hull {
+    fn new(palette: &[Color]) -> Option<Hull> {
+        let mut points: Vec<Vec3> = Vec::with_capacity(palette.len());
+        for color in palette.iter().filter(|c| c.alpha != 0) {
+            let Components(l, a, b) = color_to_oklab(color);
+            if !points.contains(&[l, a, b]) {
+                points.push([l, a, b]);
+            }
+        }
+
+        let faces = convex_hull(&points)?;
+
+        #[allow(clippy::cast_precision_loss)]
+        let center = points
+            .iter()
+            .fold([0.0; 3], |acc, p| add_scaled(acc, *p, 1.0 / points.len() as f64));
+
+        let planes = faces
+            .iter()
+            .map(|&[a, b, c]| {
+                let normal = cross(sub(points[b], points[a]), sub(points[c], points[a]));
+                let len = length(normal);
+                let normal = [normal[0] / len, normal[1] / len, normal[2] / len];
+                Plane {
+                    normal,
+                    offset: dot(normal, points[a]),
+                }
+            })
+            .collect();
+
+        Some(Hull {
+            planes,
+            min_l: points.iter().map(|p| p[0]).fold(f64::MAX, f64::min),
+            max_l: points.iter().map(|p| p[0]).fold(f64::MIN, f64::max),
+            center,
+        })
+    }
+
+    fn contains(&self, p: Vec3) -> bool {
+        self.planes
+            .iter()
+            .all(|plane| dot(plane.normal, p) <= plane.offset + EPSILON)
+    }
+
+    /// How far along `dir` a ray starting inside the hull at `origin` can travel before leaving
+    /// it, measured in multiples of `dir`.
+    fn exit_distance(&self, origin: Vec3, dir: Vec3) -> f64 {
+        self.planes
+            .iter()
+            .filter_map(|plane| {
+                let speed = dot(plane.normal, dir);
+                (speed > EPSILON).then(|| (plane.offset - dot(plane.normal, origin)) / speed)
+            })
+            .fold(f64::INFINITY, f64::min)
+            .max(0.0)
+    }
+
+    /// The neutral gray at lightness `l` if the palette can mix it, otherwise the point closest to
+    /// it on the way to the hull's center.
+    fn anchor(&self, l: f64) -> Vec3 {
+        let gray = [l, 0.0, 0.0];
+        if self.contains(gray) {
+            return gray;
+        }
+
+        let dir = sub(gray, self.center);
+        add_scaled(self.center, dir, self.exit_distance(self.center, dir).min(1.0))
+    }
+
+    /// Where `p` ends up in the hull, or `None` if it stays as it is.
+    fn map(&self, mode: GamutMapMode, p: Vec3) -> Option<Vec3> {
+        let [l, a, b] = p;
+        match mode {
+            GamutMapMode::None => None,
+            GamutMapMode::Clip => {
+                if self.contains(p) {
+                    return None;
+                }
+                let dir = sub(p, self.center);
+                Some(add_scaled(
+                    self.center,
+                    dir,
+                    self.exit_distance(self.center, dir).min(1.0),
+                ))
+            }
+            GamutMapMode::PreserveLightness => {
+                let p = [l.clamp(self.min_l, self.max_l), a, b];
+                if self.contains(p) {
+                    return Some(p);
+                }
+                let anchor = self.anchor(p[0]);
+                let dir = sub(p, anchor);
+                Some(add_scaled(
+                    anchor,
+                    dir,
+                    self.exit_distance(anchor, dir).min(1.0),
+                ))
+            }
+            GamutMapMode::Compress => {
+                let p = [
+                    self.min_l + l.clamp(0.0, 1.0) * (self.max_l - self.min_l),
+                    a,
+                    b,
+                ];
+                let anchor = self.anchor(p[0]);
+                let dir = sub(p, anchor);
+                let boundary = self.exit_distance(anchor, dir);
+                if boundary.is_finite() && boundary > EPSILON {
+                    Some(add_scaled(
+                        anchor,
+                        dir,
+                        soft_knee(1.0 / boundary) * boundary,
+                    ))
+                } else {
+                    // the anchor got clamped onto the surface and `p` is on the outside of it, or
+                    // `p` is the anchor
+                    Some(anchor)
+                }
+            }
+        }
+    }
+}
+
+/// Incremental 3D convex hull. Returns the faces as outward facing (counter-clockwise) triangles
+/// of indices into `points`, or `None` if the points are all on one plane.
+fn convex_hull(points: &[Vec3]) -> Option<Vec<[usize; 3]>> {
+    if points.len() < 4 {
+        return None;
+    }
+
+    let farthest = |key: &dyn Fn(Vec3) -> f64| {
+        (0..points.len())
+            .map(|i| (i, key(points[i])))
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .filter(|(_, d)| *d > EPSILON)
+            .map(|(i, _)| i)
+    };
+
+    let p0 = 0;
+    let p1 = farthest(&|p| length(sub(p, points[p0])))?;
+    let edge = sub(points[p1], points[p0]);
+    let p2 = farthest(&|p| length(cross(edge, sub(p, points[p0]))))?;
+    let base = cross(edge, sub(points[p2], points[p0]));
+    let p3 = farthest(&|p| dot(base, sub(p, points[p0])).abs())?;
+
+    let interior = [p0, p1, p2, p3]
+        .iter()
+        .fold([0.0; 3], |acc, i| add_scaled(acc, points[*i], 0.25));
+
+    let orient = |[a, b, c]: [usize; 3]| {
+        let normal = cross(sub(points[b], points[a]), sub(points[c], points[a]));
+        if dot(normal, sub(interior, points[a])) > 0.0 {
+            [a, c, b]
+        } else {
+            [a, b, c]
+        }
+    };
+    let sees = |[a, b, c]: [usize; 3], p: Vec3| {
+        let normal = cross(sub(points[b], points[a]), sub(points[c], points[a]));
+        dot(normal, sub(p, points[a])) > EPSILON * length(normal)
+    };
+
+    let mut faces = vec![
+        orient([p0, p1, p2]),
+        orient([p0, p1, p3]),
+        orient([p0, p2, p3]),
+        orient([p1, p2, p3]),
+    ];
+
+    for (i, point) in points.iter().enumerate() {
+        if [p0, p1, p2, p3].contains(&i) {
+            continue;
+        }
+
+        let (visible, hidden): (Vec<_>, Vec<_>) =
+            faces.into_iter().partition(|face| sees(*face, *point));
+        faces = hidden;
+        if visible.is_empty() {
+            continue;
+        }
+
+        let edges: Vec<(usize, usize)> = visible
+            .iter()
+            .flat_map(|&[a, b, c]| [(a, b), (b, c), (c, a)])
+            .collect();
+        for &(a, b) in &edges {
+            if !edges.contains(&(b, a)) {
+                faces.push([a, b, i]);
+            }
+        }
+    }
+
+    Some(faces)
+}
+
+fn soft_knee(ratio: f64) -> f64 {
+    if ratio <= COMPRESS_KNEE {
+        ratio
+    } else {
+        COMPRESS_KNEE + (1.0 - COMPRESS_KNEE) * ((ratio - COMPRESS_KNEE) / (1.0 - COMPRESS_KNEE)).tanh()
+    }
+}
+
+/// Maps every color of `input` into the hull of `palette`, leaving alpha untouched.
+///
+/// Palettes that don't span a volume (fewer than four colors, or all grays) have no hull to map
+/// into, so the input is returned as-is.
+pub fn gamut_map(input: &[Color], palette: &[Color], mode: GamutMapMode) -> Vec<Color> {
+    let hull = match mode {
+        GamutMapMode::None => None,
+        _ => Hull::new(palette),
+    };
+    let Some(hull) = hull else {
+        return input.to_vec();
+    };
+
+    input
+        .iter()
+        .map(|color| {
+            let Components(l, a, b) = color_to_oklab(color);
+            match hull.map(mode, [l, a, b]) {
+                Some([l, a, b]) => oklab_to_color(&Components(l, a, b), color.alpha),
+                None => *color,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapped_points(palette: &[Color], mode: GamutMapMode) -> (Hull, Vec<Vec3>) {
+        let hull = Hull::new(palette).expect("the palette spans a volume");
+        let points = (0..17 * 17 * 17)
+            .map(|i| {
+                let channel = |c: usize| u8::try_from(c % 17 * 255 / 16).unwrap();
+                let Components(l, a, b) = color_to_oklab(&Color::new(
+                    channel(i),
+                    channel(i / 17),
+                    channel(i / 289),
+                    255,
+                ));
+                hull.map(mode, [l, a, b]).unwrap_or([l, a, b])
+            })
+            .collect();
+        (hull, points)
+    }
+
+    #[test]
+    fn maps_into_hulls_away_from_grays() {
+        // reds and oranges only, the gray axis misses the hull at every lightness
+        let palette = [
+            Color::new(255, 0, 0, 255),
+            Color::new(160, 0, 0, 255),
+            Color::new(255, 120, 0, 255),
+            Color::new(150, 20, 60, 255),
+            Color::new(255, 60, 90, 255),
+        ];
+        for mode in [
+            GamutMapMode::Clip,
+            GamutMapMode::Compress,
+            GamutMapMode::PreserveLightness,
+        ] {
+            let (hull, points) = mapped_points(&palette, mode);
+            for p in points {
+                assert!(hull.contains(p), "{p:?} is outside of the hull");
+            }
+        }
+    }
+
+    #[test]
+    fn maps_into_hulls_around_grays() {
+        let palette = [
+            Color::new(0, 0, 0, 255),
+            Color::new(255, 255, 255, 255),
+            Color::new(200, 40, 40, 255),
+            Color::new(40, 160, 60, 255),
+            Color::new(50, 60, 200, 255),
+        ];
+        for mode in [
+            GamutMapMode::Clip,
+            GamutMapMode::Compress,
+            GamutMapMode::PreserveLightness,
+        ] {
+            let (hull, points) = mapped_points(&palette, mode);
+            for p in points {
+                assert!(hull.contains(p), "{p:?} is outside of the hull");
+            }
+        }
+    }
+
+    #[test]
+    fn flat_palettes_leave_colors_alone() {
+        let input = [Color::new(10, 200, 30, 255), Color::new(255, 0, 255, 128)];
+        let grays = [
+            Color::new(0, 0, 0, 255),
+            Color::new(85, 85, 85, 255),
+            Color::new(170, 170, 170, 255),
+            Color::new(255, 255, 255, 255),
+        ];
+        assert!(gamut_map(&input, &grays, GamutMapMode::Compress) == input);
+        assert!(gamut_map(&input, &grays[..3], GamutMapMode::Clip) == input);
+    }
+}
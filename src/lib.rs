@@ -1,20 +1,41 @@
+#![feature(portable_simd)]
 #![warn(clippy::pedantic)]
 #![allow(clippy::many_single_char_names)]
 
-use std::{borrow::Borrow, io::{BufWriter, Cursor}};
+use std::{borrow::Cow, sync::Arc};
 
-use anyhow::Result;
-use dither::dither_image;
-pub use dither::{DistanceMode, DitherMode};
-use image::{load_from_memory, write_buffer_with_format, ColorType, GenericImageView, ImageBuffer, ImageError};
-use palette::{
-    rgb::{FromHexError, Rgba},
-    FromColor, Hsva, Srgb,
+use dither::{dither_image, kmeans_palette, select_palette_subset_with, PaletteTable};
+use encode::encode_png;
+pub use encode::encode_indexed_png;
+pub use dither::{
+    quantize_median_cut, quantize_octree, quantize_wu, select_palette_subset, DistanceMode,
+    DitherMode, KMeansSeeding, PaletteMethod,
 };
-use sampling::{sample_image, SampleMode};
-pub use sprite::Sprite;
+use gamut::gamut_map;
+pub use error::Error;
+pub use gamut::GamutMapMode;
+use load::load_image;
+pub use load::DecodeLimits;
+use image::{DynamicImage, GenericImageView};
+pub use palettes::{
+    builtin_palettes, parse_color, parse_colors, parse_palette, parse_palette_as, write_palette,
+    BuiltinPalette, BuiltinPaletteInfo, ColorParseError, PaletteEntry, PaletteError,
+    PaletteFormat,
+};
+use palette::{rgb::Rgba, FromColor, Hsva, Srgb};
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use rayon::{iter::ParallelIterator, slice::ParallelSliceMut};
+pub use sampling::FitMode;
+use sampling::{fit, sample_image, Layout, SampleMode};
+pub use sprite::{IndexedSprite, Indices, RawImage, Sprite};
 
 mod dither;
+mod encode;
+mod error;
+mod gamut;
+mod load;
+mod palettes;
 mod sampling;
 mod sprite;
 
@@ -26,115 +47,448 @@ pub use wasm_bindgen_rayon::init_thread_pool;
 #[derive(Clone)]
 pub struct I2PState {
     pub(crate) sample_options: SampleOptions,
-    pub(crate) sample_step: Option<Vec<Color>>,
+    pub(crate) output_width: Option<usize>,
+    pub(crate) output_height: Option<usize>,
+    pub(crate) fit_mode: FitMode,
+    /// Holds on to its size, output sizes change without going through a setter when
+    /// `process_sprite` gets called with different sprites.
+    pub(crate) sample_step: Option<Arc<Sprite>>,
 
     pub(crate) pre_process_options: PreProcessOptions,
-    pub(crate) pre_process_step: Option<Vec<Color>>,
+    pub(crate) pre_process_step: Option<Arc<Vec<Color>>>,
     pub(crate) dither_options: DitherOptions,
 
-    pub(crate) dither_step: Option<Sprite>,
+    pub(crate) dither_step: Option<Arc<IndexedSprite>>,
     pub(crate) image_outline: Option<usize>,
     pub(crate) image_inline: Option<usize>,
-    pub(crate) palette_weight: i32,
+    pub(crate) post_process_step: Option<Arc<IndexedSprite>>,
+    pub(crate) palette_weight: f64,
 
     pub(crate) palette: Vec<Color>,
+    /// The palette in the color space of the last distance mode it was dithered with. Not a step,
+    /// it checks its own key so it survives anything that doesn't touch the palette or the mode.
+    pub(crate) palette_table: Option<PaletteTable>,
+    pub(crate) palette_budget: Option<usize>,
+    pub(crate) palette_subset: Option<Vec<usize>>,
 
-    pub(crate) input: Sprite
+    // the input and the steps are shared, cloning the state or reusing a step doesn't copy them
+    pub(crate) input: Arc<Sprite>,
+    pub(crate) rng: ChaCha8Rng,
 }
 
 #[wasm_bindgen]
 impl I2PState {
+    /// A state with default options, an empty palette and no input. Set those with `palette` and
+    /// `set_input` before asking for an image.
+    #[wasm_bindgen(constructor)]
+    #[must_use]
+    pub fn new() -> I2PState {
+        I2PState::default()
+    }
+
+    /// Replaces the input with an encoded image, decoded within `limits` or the default limits.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the image can't be decoded within the limits.
+    pub fn set_input(&mut self, bytes: &[u8], limits: Option<DecodeLimits>) -> Result<(), Error> {
+        let image = load_image(bytes, &limits.unwrap_or_default())?;
+        self.replace_input(sprite_from_image(&image));
+        Ok(())
+    }
+
     pub fn dither_options(&mut self, options: DitherOptions) {
-        self.dither_options = options;
-        self.dither_step = None;
+        if self.dither_options != options {
+            self.dither_options = options;
+            self.invalidate_from(Step::Dither);
+        }
     }
 
     pub fn sample_options(&mut self, options: SampleOptions) {
-        self.sample_options = options;
-        self.sample_step = None;
-        self.pre_process_step = None;
-        self.dither_step = None;
+        if self.sample_options != options {
+            self.sample_options = options;
+            self.invalidate_from(Step::Sample);
+        }
     }
 
     pub fn pre_process_options(&mut self, options: PreProcessOptions) {
-        self.pre_process_options = options;
-        self.pre_process_step = None;
-        self.dither_step = None;
+        if self.pre_process_options != options {
+            self.pre_process_options = options;
+            self.invalidate_from(Step::PreProcess);
+        }
+    }
+
+    /// Replaces the palette with the given colors, in any format `parse_color` understands.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error naming the first color that can't be parsed.
+    #[allow(clippy::needless_pass_by_value)]
+    pub fn palette(&mut self, palette: Vec<String>) -> Result<(), Error> {
+        let palette = parse_colors(&palette)?;
+        if self.palette == palette {
+            return Ok(());
+        }
+        self.palette = palette;
+        // only gamut mapping looks at the palette before dithering. the dither step goes either
+        // way, which also covers KMeans pulling towards the old palette
+        if self.pre_process_options.gamut_map_mode == GamutMapMode::None {
+            self.invalidate_from(Step::Dither);
+        } else {
+            self.invalidate_from(Step::PreProcess);
+        }
+        Ok(())
+    }
+
+    /// Index of the palette color drawn over visible pixels that touch transparency or the border
+    /// of the image. Wins over the inline where both are set.
+    pub fn image_outline(&mut self, index: Option<usize>) {
+        if self.image_outline != index {
+            self.image_outline = index;
+            self.invalidate_from(Step::PostProcess);
+        }
+    }
+
+    /// Index of the palette color drawn over the same pixels as the outline.
+    pub fn image_inline(&mut self, index: Option<usize>) {
+        if self.image_inline != index {
+            self.image_inline = index;
+            self.invalidate_from(Step::PostProcess);
+        }
+    }
+
+    /// How strongly `KMeans` pulls clusters towards their palette entry, from 0 to 1.
+    pub fn palette_weight(&mut self, weight: f64) {
+        if self.palette_weight.to_bits() == weight.to_bits() {
+            return;
+        }
+        self.palette_weight = weight;
+        if self.dither_options.pixel_distance_mode == DistanceMode::KMeans {
+            self.invalidate_from(Step::Dither);
+        }
+    }
+
+    /// Limits dithering to the `budget` palette entries that reproduce the image best. Outline and
+    /// inline indices keep referring to the full palette. A budget of 0 doesn't validate.
+    pub fn palette_budget(&mut self, budget: Option<usize>) {
+        if self.palette_budget != budget {
+            self.palette_budget = budget;
+            self.invalidate_from(Step::Dither);
+        }
+    }
+
+    /// Indices of the palette entries the last dither was limited to by the palette budget.
+    #[wasm_bindgen(getter)]
+    #[must_use]
+    pub fn palette_subset(&self) -> Option<Vec<usize>> {
+        self.palette_subset.clone()
+    }
+
+    /// Checks the state for anything that would keep it from being processed: an empty palette,
+    /// outline or inline indices past its end, a palette budget of 0, dither modes that aren't
+    /// implemented, a zero output size, and an empty or inconsistent input.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an `InvalidOptions` error describing the first problem found.
+    pub fn validate(&self) -> Result<(), Error> {
+        self.validate_settings()?;
+        if self.output_width == Some(0) || self.output_height == Some(0) {
+            return Err(Error::InvalidOptions(format!(
+                "the output size is {}x{}",
+                self.output_width.map_or("auto".to_string(), |w| w.to_string()),
+                self.output_height.map_or("auto".to_string(), |h| h.to_string())
+            )));
+        }
+
+        validate_sprite("input", &self.input)
     }
 
-    pub fn palette(&mut self, palette: Vec<String>) -> Result<(), JsError> {
-        self.palette = palette.iter().map(|s| s.parse()).collect::<Result<Vec<_>, FromHexError>>().map_err(|e| JsError::from(e))?;
-        self.dither_step = None;
+    /// Runs every step that isn't cached yet and packs the result into a PNG, paletted if the
+    /// palette has no more than 255 colors.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the state doesn't validate, or the result can't be
+    /// packed into a PNG.
+    pub fn image(&mut self) -> Result<Vec<u8>, Error> {
+        let output = self.render()?;
+        encode_png(&output)
+    }
+
+    /// Replaces the input with RGBA8 pixels, like the `data` of a canvas `ImageData`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an `InvalidOptions` error if `data` doesn't hold
+    /// `width * height * 4` bytes.
+    pub fn set_input_rgba(&mut self, data: &[u8], width: usize, height: usize) -> Result<(), Error> {
+        self.replace_input(Sprite::from_rgba(data, width, height)?);
         Ok(())
     }
 
-    pub fn image(&mut self) -> Result<Vec<u8>, JsError> {
-        let mut output = self.input.clone();
-        process_sprite(self, &self.input.clone(), &mut output);
+    /// Like `image`, but returns the pixels as RGBA8 instead of encoding a PNG.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the state doesn't validate.
+    pub fn image_rgba(&mut self) -> Result<RawImage, Error> {
+        let output = self.render()?;
+        Ok(RawImage::from(&*output))
+    }
 
-        #[allow(clippy::cast_possible_truncation)]
-    let mut imgbuf: ImageBuffer<image::Rgba<u8>, Vec<_>> =
-        ImageBuffer::new(output.width as u32, output.height as u32);
-    for (x, y, pixel) in imgbuf.enumerate_pixels_mut() {
-        let sprite_pixel = output.get_pixel(x as usize, y as usize).unwrap();
-        *pixel = image::Rgba([
-            sprite_pixel.red,
-            sprite_pixel.green,
-            sprite_pixel.blue,
-            sprite_pixel.alpha,
-        ]);
+    /// Sets the size of the output. Leaving out one dimension keeps the aspect ratio of the
+    /// input, leaving out both keeps its size. `fit` only matters with both set.
+    pub fn output_size(&mut self, width: Option<usize>, height: Option<usize>, fit: FitMode) {
+        if (self.output_width, self.output_height, self.fit_mode) == (width, height, fit) {
+            return;
+        }
+        self.output_width = width;
+        self.output_height = height;
+        self.fit_mode = fit;
+        self.invalidate_from(Step::Sample);
     }
 
-    let mut output_image = Cursor::new(Vec::new());
-    write_buffer_with_format(
-        &mut BufWriter::new(&mut output_image),
-        &imgbuf,
-        imgbuf.width(),
-        imgbuf.height(),
-        ColorType::Rgba8,
-        image::ImageFormat::Png,
-    )?;
-    Ok(output_image.into_inner())
+    /// Width of the image the current input and output size make.
+    #[wasm_bindgen(getter)]
+    #[must_use]
+    pub fn output_width(&self) -> usize {
+        self.layout().width
     }
+
+    /// Height of the image the current input and output size make.
+    #[wasm_bindgen(getter)]
+    #[must_use]
+    pub fn output_height(&self) -> usize {
+        self.layout().height
+    }
+}
+
+/// The cached steps of `process_sprite`, in the order they run.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Step {
+    Sample,
+    PreProcess,
+    Dither,
+    PostProcess,
+}
+
+impl I2PState {
+    fn layout(&self) -> Layout {
+        fit(
+            self.input.width,
+            self.input.height,
+            self.output_width,
+            self.output_height,
+            self.fit_mode,
+        )
+    }
+
+    /// Processes the input at the output size, cropped for `FitMode::Cover`.
+    fn render(&mut self) -> Result<Arc<IndexedSprite>, Error> {
+        self.validate()?;
+        let layout = self.layout();
+        let [x, y, width, height] = layout.crop;
+        let input = Arc::clone(&self.input);
+        Ok(run_steps(self, layout.width, layout.height, || {
+            if (width, height) == (input.width, input.height) {
+                Cow::Borrowed(&*input)
+            } else {
+                Cow::Owned(input.crop(x, y, width, height))
+            }
+        }))
+    }
+
+    fn replace_input(&mut self, input: Sprite) {
+        self.input = Arc::new(input);
+        self.invalidate_from(Step::Sample);
+    }
+
+    /// Drops the cached result of `step` and of every step after it.
+    fn invalidate_from(&mut self, step: Step) {
+        if step <= Step::Sample {
+            self.sample_step = None;
+        }
+        if step <= Step::PreProcess {
+            self.pre_process_step = None;
+        }
+        if step <= Step::Dither {
+            self.dither_step = None;
+        }
+        self.post_process_step = None;
+    }
+
+    fn validate_settings(&self) -> Result<(), Error> {
+        validate_palette_options(
+            self.palette.len(),
+            self.image_outline,
+            self.image_inline,
+            self.palette_budget,
+            self.dither_options.pixel_dither_mode,
+            self.dither_options.pixel_distance_mode,
+        )
+    }
+}
+
+fn validate_palette_options(
+    palette_len: usize,
+    image_outline: Option<usize>,
+    image_inline: Option<usize>,
+    palette_budget: Option<usize>,
+    dither_mode: DitherMode,
+    distance_mode: DistanceMode,
+) -> Result<(), Error> {
+    if palette_len == 0 {
+        return Err(Error::InvalidOptions("the palette is empty".to_string()));
+    }
+    // dithered pixels are u16 indices, with one more for transparency
+    if palette_len >= usize::from(u16::MAX) {
+        return Err(Error::InvalidOptions(format!(
+            "the palette has {palette_len} colors, the most it can have is {}",
+            u16::MAX - 1
+        )));
+    }
+    for (what, index) in [("image_outline", image_outline), ("image_inline", image_inline)] {
+        if let Some(index) = index.filter(|i| *i >= palette_len) {
+            return Err(Error::InvalidOptions(format!(
+                "{what} is {index}, but the palette only has {palette_len} colors"
+            )));
+        }
+    }
+    if palette_budget == Some(0) {
+        return Err(Error::InvalidOptions(
+            "palette_budget is 0, it has to leave at least one color".to_string(),
+        ));
+    }
+    // k-means does its own thing for the floyd modes, everything else would hit a todo
+    if distance_mode != DistanceMode::KMeans
+        && matches!(dither_mode, DitherMode::FloydComponent | DitherMode::FloydDistributed)
+    {
+        return Err(Error::InvalidOptions(
+            "Floyd-Steinberg dithering is only implemented for the KMeans distance mode"
+                .to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn validate_sprite(what: &str, sprite: &Sprite) -> Result<(), Error> {
+    if sprite.width == 0 || sprite.height == 0 {
+        return Err(Error::InvalidOptions(format!(
+            "the {what} is empty ({}x{})",
+            sprite.width, sprite.height
+        )));
+    }
+    if sprite.data.len() != sprite.width * sprite.height {
+        return Err(Error::InvalidOptions(format!(
+            "the {what} has {} pixels, but is {}x{}",
+            sprite.data.len(),
+            sprite.width,
+            sprite.height
+        )));
+    }
+    Ok(())
 }
 
 #[wasm_bindgen]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq)]
 pub struct DitherOptions {
+    /// Seeds every random choice made while dithering.
+    pub seed: u64,
     pub dither_amount: f32,
     pub alpha_threshold: u8,
     pub pixel_dither_mode: DitherMode,
     pub pixel_distance_mode: DistanceMode,
+    pub kmeans_max_iterations: usize,
+}
+
+#[wasm_bindgen]
+impl DitherOptions {
+    #[wasm_bindgen(constructor)]
+    #[must_use]
+    pub fn new() -> DitherOptions {
+        DitherOptions {
+            seed: 0,
+            dither_amount: 64.0,
+            alpha_threshold: 128,
+            pixel_dither_mode: DitherMode::default(),
+            pixel_distance_mode: DistanceMode::default(),
+            kmeans_max_iterations: 16,
+        }
+    }
 }
 
 impl Default for DitherOptions {
     fn default() -> Self {
-        Self { dither_amount: 64.0,
-            alpha_threshold: 128, pixel_dither_mode: Default::default(), pixel_distance_mode: Default::default() }
+        Self::new()
     }
 }
 
 #[wasm_bindgen]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq)]
 pub struct PreProcessOptions {
     pub brightness: f64,
     pub contrast: f64,
     pub gamma: f64,
     pub saturation: f64,
-    pub hue: f64
+    pub hue: f64,
+    pub gamut_map_mode: GamutMapMode,
 }
 
 #[wasm_bindgen]
-#[derive(Clone, Copy)]
+impl PreProcessOptions {
+    /// Options that leave the colors as they are.
+    #[wasm_bindgen(constructor)]
+    #[must_use]
+    pub fn new() -> PreProcessOptions {
+        PreProcessOptions {
+            brightness: 0.0,
+            contrast: 0.0,
+            gamma: 100.0,
+            saturation: 100.0,
+            hue: 0.0,
+            gamut_map_mode: GamutMapMode::default(),
+        }
+    }
+}
+
+impl Default for PreProcessOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[wasm_bindgen]
+#[derive(Clone, Copy, PartialEq)]
 pub struct SampleOptions {
     pub sample_mode: SampleMode,
     pub offset_x: i32,
     pub offset_y: i32,
 }
 
+#[wasm_bindgen]
+impl SampleOptions {
+    #[wasm_bindgen(constructor)]
+    #[must_use]
+    pub fn new() -> SampleOptions {
+        SampleOptions {
+            sample_mode: SampleMode::default(),
+            offset_x: 0,
+            offset_y: 0,
+        }
+    }
+}
+
+impl Default for SampleOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[wasm_bindgen]
 #[derive(Clone, Copy)]
 pub struct PixelizationOptions {
+    pub seed: u64,
     pub brightness: Option<f64>,
     pub contrast: Option<f64>,
     pub gamma: Option<f64>,
@@ -149,16 +503,26 @@ pub struct PixelizationOptions {
     pub pixel_sample_mode: SampleMode,
     pub pixel_dither_mode: DitherMode,
     pub pixel_distance_mode: DistanceMode,
+    pub kmeans_max_iterations: usize,
+    pub gamut_map_mode: GamutMapMode,
+    /// Size of the output image, the input gets sampled to it.
     pub image_out_width: i32,
     pub image_out_height: i32,
-    pub palette_weight: i32,
+    /// How the output size is fit to the aspect ratio of the input.
+    pub fit_mode: FitMode,
+    /// How strongly `KMeans` pulls clusters towards their palette entry, from 0 to 1.
+    pub palette_weight: f64,
+    pub palette_budget: Option<usize>,
+    pub decode_limits: DecodeLimits,
 }
 
 #[wasm_bindgen]
 impl PixelizationOptions {
     #[wasm_bindgen(constructor)]
+    #[must_use]
     pub fn new() -> PixelizationOptions {
         PixelizationOptions {
+            seed: 0,
             brightness: Some(0.0),
             contrast: Some(0.0),
             gamma: Some(100.0),
@@ -173,109 +537,219 @@ impl PixelizationOptions {
             pixel_sample_mode: SampleMode::default(),
             pixel_dither_mode: DitherMode::default(),
             pixel_distance_mode: DistanceMode::default(),
+            kmeans_max_iterations: 16,
+            gamut_map_mode: GamutMapMode::default(),
             image_out_width: 128,
             image_out_height: 128,
-            palette_weight: 2,
+            fit_mode: FitMode::default(),
+            palette_weight: 0.2,
+            palette_budget: None,
+            decode_limits: DecodeLimits::new(),
+        }
+    }
+}
+
+impl PixelizationOptions {
+    /// Checks the options against a palette of `palette_len` colors, the same way
+    /// `I2PState::validate` does, and that the output size is positive.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an `InvalidOptions` error describing the first problem found.
+    pub fn validate(&self, palette_len: usize) -> Result<(), Error> {
+        validate_palette_options(
+            palette_len,
+            self.image_outline,
+            self.image_inline,
+            self.palette_budget,
+            self.pixel_dither_mode,
+            self.pixel_distance_mode,
+        )?;
+        if self.image_out_width <= 0 || self.image_out_height <= 0 {
+            return Err(Error::InvalidOptions(format!(
+                "the output size is {}x{}",
+                self.image_out_width, self.image_out_height
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Default for PixelizationOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[wasm_bindgen]
+#[derive(Clone, Copy)]
+pub struct ExtractPaletteOptions {
+    pub method: PaletteMethod,
+    pub seed: u64,
+    pub seeding: KMeansSeeding,
+    pub distance_mode: DistanceMode,
+    pub max_iterations: usize,
+    pub decode_limits: DecodeLimits,
+}
+
+#[wasm_bindgen]
+impl ExtractPaletteOptions {
+    #[wasm_bindgen(constructor)]
+    #[must_use]
+    pub fn new() -> ExtractPaletteOptions {
+        ExtractPaletteOptions {
+            method: PaletteMethod::default(),
+            seed: 0,
+            seeding: KMeansSeeding::default(),
+            distance_mode: DistanceMode::default(),
+            max_iterations: 16,
+            decode_limits: DecodeLimits::new(),
         }
     }
 }
 
+impl Default for ExtractPaletteOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Default for I2PState {
     fn default() -> Self {
         Self {
-            sample_options: SampleOptions {
-                sample_mode: SampleMode::default(),
-                offset_x: 0,
-                offset_y: 0,
-            },
+            sample_options: SampleOptions::new(),
+            output_width: None,
+            output_height: None,
+            fit_mode: FitMode::default(),
             sample_step: None,
-            pre_process_options: PreProcessOptions {
-                brightness: 0.0,
-                contrast: 0.0,
-                gamma: 100.0,
-                saturation: 100.0,
-                hue: 0.0
-            },
+            pre_process_options: PreProcessOptions::new(),
             pre_process_step: None,
-            dither_options: DitherOptions {
-                dither_amount: 64.0,
-                alpha_threshold: 128,
-                pixel_dither_mode: DitherMode::default(),
-                pixel_distance_mode: DistanceMode::default(),
-            },
+            dither_options: DitherOptions::new(),
             dither_step: None,
             image_outline: None,
             image_inline: None,
-            palette_weight: 2,
-            palette: Default::default(),
-            input: Default::default()
+            post_process_step: None,
+            palette_weight: 0.2,
+            palette: Vec::default(),
+            palette_table: None,
+            palette_budget: None,
+            palette_subset: None,
+            input: Arc::default(),
+            rng: ChaCha8Rng::seed_from_u64(0),
         }
     }
 }
 
 pub type Color = Rgba<Srgb, u8>;
+/// What pixels below the alpha threshold become. `Color::default()` is opaque black.
+pub(crate) const TRANSPARENT: Color = Color::new(0, 0, 0, 0);
+#[derive(Clone, Copy)]
 pub struct Components(f64, f64, f64);
 
+/// Formats a color as `#RRGGBBAA`, which round trips through the palette parser.
+#[must_use]
+pub fn color_to_hex(color: &Color) -> String {
+    format!(
+        "#{:02X}{:02X}{:02X}{:02X}",
+        color.red, color.green, color.blue, color.alpha
+    )
+}
+
+fn sprite_from_image(image: &DynamicImage) -> Sprite {
+    let mut sprite = Sprite {
+        width: image.width() as usize,
+        height: image.height() as usize,
+        data: vec![Color::default(); image.width() as usize * image.height() as usize],
+    };
+    for (x, y, pixel) in image.pixels() {
+        let pixel = pixel.0;
+        sprite.set_pixel(
+            x as usize,
+            y as usize,
+            Color::new(pixel[0], pixel[1], pixel[2], pixel[3]),
+        );
+    }
+    sprite
+}
+
 #[wasm_bindgen]
 #[derive(Clone)]
 pub struct ProcessOutput {
     image: Vec<u8>,
+    indexed: Arc<IndexedSprite>,
     state: I2PState
 }
 
 #[wasm_bindgen]
 impl ProcessOutput {
     #[wasm_bindgen(getter)]
+    #[must_use]
     pub fn image(&self) -> Vec<u8> {
         self.image.clone()
     }
 
     #[wasm_bindgen(getter)]
+    #[must_use]
     pub fn state(&self) -> I2PState {
         self.state.clone()
     }
 }
 
+impl ProcessOutput {
+    /// The result as palette indices, before it was packed into a PNG.
+    #[must_use]
+    pub fn indexed(&self) -> &IndexedSprite {
+        &self.indexed
+    }
+}
+
 /// WASM-friendly wrapper for process_image.
 ///
-/// # Panics
-///
-/// Panics if the output image can't be placed into an image buffer due to a dimension mismatch.
-///
 /// # Errors
 ///
-/// This function will return an error if the provided palette cannot be parsed, the provided image cannot be loaded, or the result can't be packed into a PNG.
+/// This function will return an error if the image can't be decoded within the decode limits,
+/// the palette can't be parsed, the options don't validate, or the result can't be packed into a
+/// PNG.
 #[wasm_bindgen]
+#[allow(clippy::needless_pass_by_value)]
 pub fn process_image_wasm(
     input: &[u8],
     palette: Vec<String>,
     options: PixelizationOptions,
-) -> Result<ProcessOutput, JsError> {
-    let result = process_image(input, &palette, options);
-    result.map_err(|e| JsError::new(&format!("{e}")))
+) -> Result<ProcessOutput, Error> {
+    process_image(input, &palette, options)
 }
 
-/// .
-///
-/// # Panics
-///
-/// Panics if the output image can't be placed into an image buffer due to a dimension mismatch.
+/// Pixelizes an encoded image in one go: samples it down to the output size, color corrects it,
+/// dithers it to the palette and draws the outlines. Returns the result as a PNG along with the
+/// indexed pixels and the state, which can be kept for cheap re-renders.
 ///
 /// # Errors
 ///
-/// This function will return an error if the provided palette cannot be parsed, the provided image cannot be loaded, or the result can't be packed into a PNG.
+/// This function will return an error if the image can't be decoded within the decode limits,
+/// the palette can't be parsed, the options don't validate, or the result can't be packed into a
+/// PNG.
 pub fn process_image(
     input: &[u8],
     palette: &[String],
     options: PixelizationOptions,
-) -> Result<ProcessOutput> {
-    let image = load_from_memory(input)?;
-    let palette: Vec<Color> = palette
-        .iter()
-        .map(|c| c.parse::<Color>())
-        .collect::<Result<Vec<_>, FromHexError>>()?;
-
-    let mut state = I2PState {
+) -> Result<ProcessOutput, Error> {
+    let image = load_image(input, &options.decode_limits)?;
+    let palette = parse_colors(palette)?;
+    options.validate(palette.len())?;
+
+    let mut state = state_from_options(palette, &options);
+    state.input = Arc::new(sprite_from_image(&image));
+    let indexed = state.render()?;
+    Ok(ProcessOutput {
+        image: encode_png(&indexed)?,
+        indexed,
+        state,
+    })
+}
+
+fn state_from_options(palette: Vec<Color>, options: &PixelizationOptions) -> I2PState {
+    I2PState {
         sample_options: SampleOptions {
           sample_mode: options.pixel_sample_mode,
           offset_x: options.offset_x,
@@ -286,69 +760,221 @@ pub fn process_image(
             contrast: options.contrast.unwrap_or(0.0),
             gamma: options.gamma.unwrap_or(100.0),
             saturation: options.saturation.unwrap_or(100.0),
-            hue: options.hue.unwrap_or(0.0)
+            hue: options.hue.unwrap_or(0.0),
+            gamut_map_mode: options.gamut_map_mode,
         },
-        dither_options: DitherOptions {dither_amount: options.dither_amount,
+        dither_options: DitherOptions {seed: options.seed,
+            dither_amount: options.dither_amount,
             alpha_threshold: options.alpha_threshold,
             pixel_dither_mode: options.pixel_dither_mode,
-            pixel_distance_mode: options.pixel_distance_mode,},
+            pixel_distance_mode: options.pixel_distance_mode,
+            kmeans_max_iterations: options.kmeans_max_iterations,},
         image_outline: options.image_outline,
         image_inline: options.image_inline,
         palette_weight: options.palette_weight,
-        palette: palette,
+        palette,
+        palette_budget: options.palette_budget,
+        // validated to be positive
+        output_width: usize::try_from(options.image_out_width).ok(),
+        output_height: usize::try_from(options.image_out_height).ok(),
+        fit_mode: options.fit_mode,
         ..Default::default()
-    };
+    }
+}
 
-    let mut output = Sprite {
-        width: image.width() as usize,
-        height: image.height() as usize,
-        data: vec![Color::default(); image.width() as usize * image.height() as usize],
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct RawProcessOutput {
+    image: RawImage,
+    state: I2PState,
+}
+
+#[wasm_bindgen]
+impl RawProcessOutput {
+    #[wasm_bindgen(getter)]
+    #[must_use]
+    pub fn image(&self) -> RawImage {
+        self.image.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    #[must_use]
+    pub fn state(&self) -> I2PState {
+        self.state.clone()
+    }
+}
+
+impl RawProcessOutput {
+    #[must_use]
+    pub fn into_image(self) -> RawImage {
+        self.image
+    }
+}
+
+/// WASM-friendly wrapper for `process_rgba`.
+///
+/// # Errors
+///
+/// This function will return an error if `data` doesn't hold `width * height * 4` bytes, the
+/// palette can't be parsed, or the options don't validate.
+#[wasm_bindgen]
+#[allow(clippy::needless_pass_by_value)]
+pub fn process_rgba_wasm(
+    data: &[u8],
+    width: usize,
+    height: usize,
+    palette: Vec<String>,
+    options: PixelizationOptions,
+) -> Result<RawProcessOutput, Error> {
+    process_rgba(data, width, height, &palette, options)
+}
+
+/// Like `process_image`, but takes and returns RGBA8 pixels instead of encoded images, so live
+/// previews skip both codecs.
+///
+/// # Errors
+///
+/// This function will return an error if `data` doesn't hold `width * height * 4` bytes, the
+/// palette can't be parsed, or the options don't validate.
+pub fn process_rgba(
+    data: &[u8],
+    width: usize,
+    height: usize,
+    palette: &[String],
+    options: PixelizationOptions,
+) -> Result<RawProcessOutput, Error> {
+    let input = Sprite::from_rgba(data, width, height)?;
+    let palette = parse_colors(palette)?;
+    options.validate(palette.len())?;
+
+    let mut state = state_from_options(palette, &options);
+    state.input = Arc::new(input);
+    let output = state.render()?;
+    Ok(RawProcessOutput {
+        image: RawImage::from(&*output),
+        state,
+    })
+}
+
+/// WASM-friendly wrapper for `extract_palette`.
+///
+/// # Errors
+///
+/// This function will return an error if the provided image cannot be loaded.
+#[wasm_bindgen]
+pub fn extract_palette_wasm(
+    input: &[u8],
+    k: usize,
+    options: ExtractPaletteOptions,
+) -> Result<Vec<String>, Error> {
+    let image = load_image(input, &options.decode_limits)?;
+    Ok(extract_palette(&sprite_from_image(&image), k, options))
+}
+
+/// Generates a palette of up to `k` colors for the visible pixels of `sprite`. The default method
+/// runs k-means with free centroids, runs with the same seed give the same palette. The seeding,
+/// distance mode and iteration options only apply to k-means.
+///
+/// Duplicate colors (from images with fewer than `k` distinct colors) are only returned once.
+#[must_use]
+pub fn extract_palette(sprite: &Sprite, k: usize, options: ExtractPaletteOptions) -> Vec<String> {
+    let mut palette = match options.method {
+        PaletteMethod::KMeans => kmeans_palette(
+            &sprite.data,
+            k,
+            options.seeding,
+            options.distance_mode,
+            options.max_iterations,
+            &mut ChaCha8Rng::seed_from_u64(options.seed),
+        ),
+        PaletteMethod::MedianCut => quantize_median_cut(&sprite.data, k),
+        PaletteMethod::Octree => quantize_octree(&sprite.data, k),
+        PaletteMethod::Wu => quantize_wu(&sprite.data, k),
     };
-    for (x, y, pixel) in image.pixels() {
-        let pixel = pixel.0;
-        output.set_pixel(
-            x as usize,
-            y as usize,
-            Color::new(pixel[0], pixel[1], pixel[2], pixel[3]),
-        );
+    palette.iter_mut().for_each(|c| c.alpha = 255);
+
+    let mut hex: Vec<String> = Vec::with_capacity(palette.len());
+    for color in palette.iter().map(color_to_hex) {
+        if !hex.contains(&color) {
+            hex.push(color);
+        }
+    }
+    hex
+}
+
+/// Samples `input` down to the size of `output`, then color corrects, dithers and outlines it,
+/// reusing every step `s` still has cached.
+///
+/// # Errors
+///
+/// This function will return an `InvalidOptions` error if the state's settings don't validate, or
+/// either sprite is empty or doesn't hold `width * height` pixels.
+pub fn process_sprite(s: &mut I2PState, input: &Sprite, output: &mut Sprite) -> Result<(), Error> {
+    validate_sprite("output", output)?;
+    *output = process_sprite_indexed(s, input, output.width, output.height)?.to_sprite();
+    Ok(())
+}
+
+/// Like `process_sprite`, but returns the result as indices into the palette. The sprite's palette
+/// is the full palette of `s` even with a palette budget, followed by a transparent entry if any
+/// pixel ended up transparent.
+///
+/// # Errors
+///
+/// This function will return an `InvalidOptions` error if the state's settings don't validate,
+/// `input` is empty or doesn't hold `width * height` pixels, or the output size is zero.
+pub fn process_sprite_indexed(
+    s: &mut I2PState,
+    input: &Sprite,
+    width: usize,
+    height: usize,
+) -> Result<Arc<IndexedSprite>, Error> {
+    s.validate_settings()?;
+    validate_sprite("input", input)?;
+    if width == 0 || height == 0 {
+        return Err(Error::InvalidOptions(format!(
+            "the output is empty ({width}x{height})"
+        )));
+    }
+
+    Ok(run_steps(s, width, height, || Cow::Borrowed(input)))
+}
+
+/// Runs the steps `s` doesn't have cached for a `width` by `height` output. `input` only gets
+/// called when there's something to sample.
+#[allow(clippy::many_single_char_names, clippy::too_many_lines)]
+fn run_steps<'a>(
+    s: &mut I2PState,
+    width: usize,
+    height: usize,
+    input: impl FnOnce() -> Cow<'a, Sprite>,
+) -> Arc<IndexedSprite> {
+    if s
+        .sample_step
+        .as_ref()
+        .is_some_and(|step| step.width != width || step.height != height)
+    {
+        s.invalidate_from(Step::Sample);
+    }
+    if let Some(step) = &s.post_process_step {
+        return Arc::clone(step);
     }
 
-    let input = output.clone();
-    state.input = input.clone();
-    process_sprite(&mut state, &input, &mut output);
-
-    #[allow(clippy::cast_possible_truncation)]
-    let mut imgbuf: ImageBuffer<image::Rgba<u8>, Vec<_>> =
-        ImageBuffer::new(output.width as u32, output.height as u32);
-    for (x, y, pixel) in imgbuf.enumerate_pixels_mut() {
-        let sprite_pixel = output.get_pixel(x as usize, y as usize).unwrap();
-        *pixel = image::Rgba([
-            sprite_pixel.red,
-            sprite_pixel.green,
-            sprite_pixel.blue,
-            sprite_pixel.alpha,
-        ]);
-    }
-
-    let mut output_image = Cursor::new(Vec::new());
-    write_buffer_with_format(
-        &mut BufWriter::new(&mut output_image),
-        &imgbuf,
-        imgbuf.width(),
-        imgbuf.height(),
-        ColorType::Rgba8,
-        image::ImageFormat::Png,
-    )?;
-    Ok(ProcessOutput { image: output_image.into_inner(), state})
-}
-
-#[allow(clippy::many_single_char_names)]
-pub fn process_sprite(s: &mut I2PState, input: &Sprite, output: &mut Sprite) {
     println!("sample");
-    let mut temp = s.sample_step.clone().unwrap_or_else(|| sample_image(s, input, output.width, output.height));
-    s.sample_step = Some(temp.clone());
+    let sample = match &s.sample_step {
+        Some(step) => Arc::clone(step),
+        None => Arc::new(Sprite {
+            width,
+            height,
+            data: sample_image(s, &input(), width, height),
+        }),
+    };
+    s.sample_step = Some(Arc::clone(&sample));
     println!("sample done");
-    let temp = s.pre_process_step.clone().unwrap_or_else(|| {
+    let temp = match &s.pre_process_step {
+        Some(step) => Arc::clone(step),
+        None => {
+        let mut temp = sample.data.clone();
         let gamma_factor = s.pre_process_options.gamma / 100.0;
     let contrast_factor = (259.0 * (255.0 + s.pre_process_options.contrast)) / (255.0 * (259.0 - s.pre_process_options.contrast));
     let saturation_factor = s.pre_process_options.saturation / 100.0;
@@ -376,9 +1002,9 @@ pub fn process_sprite(s: &mut I2PState, input: &Sprite, output: &mut Sprite) {
     let wb = (t + brightness_factor) * 255.0;
 
     println!("color correction");
-    for y in 0..output.height {
-        for x in 0..output.width {
-            let mut input = temp[y * output.width + x];
+    temp.par_chunks_exact_mut(width).for_each(|row| {
+        for pixel in row {
+            let mut input = *pixel;
             let a = input.alpha;
 
             if s.pre_process_options.hue != 0.0 {
@@ -412,55 +1038,276 @@ pub fn process_sprite(s: &mut I2PState, input: &Sprite, output: &mut Sprite) {
             }
 
             input.alpha = a;
-            temp[y * output.width + x] = input;
+            *pixel = input;
         }
+    });
+
+    if s.pre_process_options.gamut_map_mode != GamutMapMode::None {
+        temp = gamut_map(&temp, &s.palette, s.pre_process_options.gamut_map_mode);
     }
 
-    temp
-    });
-    s.pre_process_step = Some(temp.clone());
+    Arc::new(temp)
+        }
+    };
+    s.pre_process_step = Some(Arc::clone(&temp));
     println!("color correction done");
 
     println!("dither");
-    if let Some(step) = &s.dither_step {
-        *output = step.clone();
+    let dithered = if let Some(step) = &s.dither_step {
+        Arc::clone(step)
     } else {
-        dither_image(s, &temp, output, output.width, output.height);
+        let mode = s.dither_options.pixel_distance_mode;
+        let table = match s.palette_table.take() {
+            Some(table) if table.matches(&s.palette, mode) => table,
+            _ => PaletteTable::new(&s.palette, mode),
+        };
+
+        let dithered = match s.palette_budget {
+            Some(budget) if budget < s.palette.len() => {
+                let subset = select_palette_subset_with(
+                    &temp,
+                    &table.closest.components,
+                    budget,
+                    mode,
+                    s.dither_options.alpha_threshold,
+                );
+                let palette: Vec<Color> = subset.iter().map(|i| s.palette[*i]).collect();
+                let closest = table.closest.subset(&subset, mode);
+                let dithered = dither_image(s, &palette, &closest, &temp, width, height);
+                // back to indices into the full palette, which outline and inline refer to
+                let dithered = dithered.remap(&subset, &s.palette);
+                s.palette_subset = Some(subset);
+                dithered
+            }
+            _ => {
+                s.palette_subset = None;
+                let palette = s.palette.clone();
+                dither_image(s, &palette, &table.closest, &temp, width, height)
+            }
+        };
+        s.palette_table = Some(table);
+        Arc::new(dithered)
     };
-    s.dither_step = Some(output.clone());
+    s.dither_step = Some(Arc::clone(&dithered));
     println!("dither done");
 
     println!("post process");
-    post_process_image(s, output);
+    let output = if s.image_outline.is_none() && s.image_inline.is_none() {
+        dithered
+    } else {
+        Arc::new(post_process_image(s, &dithered))
+    };
+    s.post_process_step = Some(Arc::clone(&output));
     println!("post process done");
+    output
 }
 
-fn post_process_image(s: &mut I2PState, output: &mut Sprite) {
-    let temp = output.clone();
+fn post_process_image(s: &I2PState, temp: &IndexedSprite) -> IndexedSprite {
+    let mut output = temp.clone();
 
     for y in 0..output.height {
         for x in 0..output.width {
             if let Some(inline) = s.image_inline {
-                if temp.get_pixel(x, y).unwrap().alpha != 0
-                    && (temp.get_pixel(x, y - 1).unwrap_or_default().alpha == 0
-                        || temp.get_pixel(x - 1, y).unwrap_or_default().alpha == 0
-                        || temp.get_pixel(x + 1, y).unwrap_or_default().alpha == 0
-                        || temp.get_pixel(x, y + 1).unwrap_or_default().alpha == 0)
-                {
-                    output.set_pixel(x, y, s.palette[inline]);
+                if is_edge(temp, x, y) {
+                    output.set_index(x, y, inline);
                 }
             }
 
             if let Some(outline) = s.image_outline {
-                if temp.get_pixel(x, y).unwrap().alpha != 0
-                    && (temp.get_pixel(x, y - 1).unwrap_or_default().alpha == 0
-                        || temp.get_pixel(x - 1, y).unwrap_or_default().alpha == 0
-                        || temp.get_pixel(x + 1, y).unwrap_or_default().alpha == 0
-                        || temp.get_pixel(x, y + 1).unwrap_or_default().alpha == 0)
-                {
-                    output.set_pixel(x, y, s.palette[outline]);
+                if is_edge(temp, x, y) {
+                    output.set_index(x, y, outline);
                 }
             }
         }
     }
+    output
+}
+
+/// Whether the pixel at `x`, `y` is visible and next to a transparent pixel or the border.
+fn is_edge(sprite: &IndexedSprite, x: usize, y: usize) -> bool {
+    let transparent = |x: Option<usize>, y: Option<usize>| match (x, y) {
+        (Some(x), Some(y)) if x < sprite.width && y < sprite.height => {
+            sprite.get_pixel(x, y).map_or(true, |c| c.alpha == 0)
+        }
+        _ => true,
+    };
+
+    !transparent(Some(x), Some(y))
+        && (transparent(Some(x), y.checked_sub(1))
+            || transparent(x.checked_sub(1), Some(y))
+            || transparent(x.checked_add(1), Some(y))
+            || transparent(Some(x), y.checked_add(1)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// FNV-1a over the RGBA bytes.
+    fn hash(colors: &[Color]) -> u64 {
+        colors
+            .iter()
+            .flat_map(|c| [c.red, c.green, c.blue, c.alpha])
+            .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+                (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+            })
+    }
+
+    /// The sampled and the color corrected image in every sample mode, at a larger and a smaller
+    /// size than the input.
+    fn sample_hashes() -> Vec<(u64, u64)> {
+        let (width, height) = (37, 23);
+        let data: Vec<u8> = (0..width * height * 4)
+            .map(|i| u8::try_from(i * 7919 % 251).unwrap())
+            .collect();
+        let mut s = I2PState::new();
+        s.palette(vec!["#000".into(), "#fff".into(), "#f00".into()])
+            .unwrap();
+        s.set_input_rgba(&data, width, height).unwrap();
+        s.pre_process_options(PreProcessOptions {
+            brightness: 10.0,
+            contrast: 20.0,
+            gamma: 120.0,
+            saturation: 130.0,
+            hue: 40.0,
+            ..PreProcessOptions::new()
+        });
+
+        let mut hashes = Vec::new();
+        for sample_mode in [
+            SampleMode::Round,
+            SampleMode::Floor,
+            SampleMode::Ceiling,
+            SampleMode::Linear,
+            SampleMode::Bicubic,
+            SampleMode::Lanczos,
+        ] {
+            s.sample_options(SampleOptions {
+                sample_mode,
+                offset_x: 30,
+                offset_y: 60,
+            });
+            for (width, height) in [(50, 31), (17, 11)] {
+                s.output_size(Some(width), Some(height), FitMode::Stretch);
+                s.image_rgba().unwrap();
+                hashes.push((
+                    hash(&s.sample_step.as_ref().unwrap().data),
+                    hash(s.pre_process_step.as_ref().unwrap()),
+                ));
+            }
+        }
+        hashes
+    }
+
+    #[test]
+    fn sampling_matches_the_sequential_version() {
+        // recorded with sampling and color correction still going one pixel after the other
+        let sequential = [
+            (0x8504_4ec8_418b_8627, 0x4b3f_e88d_ad78_eb8b),
+            (0x4eb0_dda9_b92a_627a, 0x5b07_d125_34b6_7254),
+            (0x38ae_7e73_d910_a29f, 0x08dd_9938_4801_da4c),
+            (0xdd7a_35c4_d26f_9c98, 0xbe9a_9782_ea8e_e6ab),
+            (0xc21a_1420_448e_9cbe, 0xbec5_2587_8baa_5674),
+            (0x3cbb_1a7f_6376_b5d1, 0x9002_4756_76d3_fbdf),
+            (0x5df4_a1c1_60da_d254, 0x4852_9b0d_ce05_63c3),
+            (0x040f_acb2_01bd_3ed5, 0x1f7d_75cc_3581_45ea),
+            (0x2f8d_3dbd_c20c_dba9, 0x9e73_f3d6_e195_ec03),
+            (0x2ce0_f156_a6dd_f6ad, 0x2cb4_8f9b_fa04_ada8),
+            (0xca3b_46f3_0339_1624, 0xbd22_b11d_5ed7_46c9),
+            (0xe123_ac77_6dc9_e16e, 0x96ad_2044_ac2e_f352),
+        ];
+        for threads in [1, 4] {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            assert_eq!(pool.install(sample_hashes), sequential, "{threads} threads");
+        }
+    }
+
+    #[test]
+    fn one_shot_processing_uses_the_output_size() {
+        let data: Vec<u8> = (0..30 * 20 * 4).map(|i| u8::try_from(i % 256).unwrap()).collect();
+        let palette = ["#000".to_string(), "#fff".to_string()];
+        for (fit_mode, width, height) in [
+            (FitMode::Stretch, 12, 12),
+            (FitMode::Contain, 12, 8),
+            (FitMode::Cover, 12, 12),
+        ] {
+            let output = process_rgba(
+                &data,
+                30,
+                20,
+                &palette,
+                PixelizationOptions {
+                    image_out_width: 12,
+                    image_out_height: 12,
+                    fit_mode,
+                    ..PixelizationOptions::new()
+                },
+            )
+            .unwrap();
+            assert_eq!((output.image.width(), output.image.height()), (width, height));
+            assert_eq!(
+                (output.state.output_width(), output.state.output_height()),
+                (width, height)
+            );
+        }
+    }
+
+    #[test]
+    fn palette_budget_of_zero_is_rejected() {
+        let options = PixelizationOptions {
+            palette_budget: Some(0),
+            ..PixelizationOptions::new()
+        };
+        assert!(matches!(options.validate(4), Err(Error::InvalidOptions(_))));
+
+        let mut s = I2PState::new();
+        s.palette(vec!["#000".into(), "#fff".into()]).unwrap();
+        s.set_input_rgba(&[0; 16], 2, 2).unwrap();
+        s.palette_budget(Some(0));
+        assert!(matches!(s.validate(), Err(Error::InvalidOptions(_))));
+        s.palette_budget(Some(1));
+        assert!(s.validate().is_ok());
+    }
+
+    /// Extracts a palette with k-means++ and dithers with k-means, both with `seed`, and returns
+    /// the PNG.
+    fn seeded_png(seed: u64) -> Vec<u8> {
+        let input = include_bytes!("../lenna.png");
+        let palette = extract_palette_wasm(
+            input,
+            12,
+            ExtractPaletteOptions {
+                seed,
+                seeding: KMeansSeeding::PlusPlus,
+                ..ExtractPaletteOptions::new()
+            },
+        )
+        .unwrap();
+        let options = PixelizationOptions {
+            seed,
+            pixel_distance_mode: DistanceMode::KMeans,
+            pixel_dither_mode: DitherMode::Bayer4x4,
+            image_out_width: 64,
+            image_out_height: 64,
+            ..PixelizationOptions::new()
+        };
+        process_image(input, &palette, options).unwrap().image
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_png() {
+        let png = seeded_png(7);
+        // the k-means sums are split across threads, that mustn't change them
+        for threads in [1, 4] {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            assert!(pool.install(|| seeded_png(7)) == png, "{threads} threads");
+        }
+        assert!(seeded_png(8) != png);
+    }
 }
@@ -0,0 +1,203 @@
+// decoding user supplied images. everything here runs on buffers straight from the browser, so
+// nothing gets decoded before its size has been checked against the limits. once decoded, images
+// get turned upright and converted to sRGB, which is what the rest of the pipeline assumes
+
+use std::io::Cursor;
+
+use exif::{In, Tag};
+use image::{
+    error::{LimitError, LimitErrorKind},
+    imageops::FilterType,
+    io::{Limits, Reader},
+    DynamicImage, ImageDecoder, ImageError, ImageFormat,
+};
+
+use crate::Error;
+
+use wasm_bindgen::prelude::*;
+
+mod profile;
+
+#[wasm_bindgen]
+#[derive(Clone, Copy)]
+pub struct DecodeLimits {
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    pub max_pixels: Option<u64>,
+    /// Bytes the decoder may allocate at once, including the decoded image.
+    pub max_alloc: Option<u64>,
+    /// Images with more pixels than this get scaled down to about this many right after decoding,
+    /// before the full resolution input is built from them.
+    pub downscale_above: Option<u64>,
+}
+
+#[wasm_bindgen]
+impl DecodeLimits {
+    #[wasm_bindgen(constructor)]
+    #[must_use]
+    pub fn new() -> DecodeLimits {
+        DecodeLimits {
+            max_width: Some(16384),
+            max_height: Some(16384),
+            max_pixels: Some(32 * 1024 * 1024),
+            max_alloc: Some(512 * 1024 * 1024),
+            downscale_above: None,
+        }
+    }
+
+    /// No limits at all, for trusted input.
+    #[must_use]
+    pub fn none() -> DecodeLimits {
+        DecodeLimits {
+            max_width: None,
+            max_height: None,
+            max_pixels: None,
+            max_alloc: None,
+            downscale_above: None,
+        }
+    }
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn too_large() -> Error {
+    Error::ImageDecode(ImageError::Limits(LimitError::from_kind(
+        LimitErrorKind::DimensionError,
+    )))
+}
+
+fn image_limits(limits: &DecodeLimits) -> Limits {
+    let mut image_limits = Limits::default();
+    image_limits.max_image_width = limits.max_width;
+    image_limits.max_image_height = limits.max_height;
+    image_limits.max_alloc = limits.max_alloc;
+    image_limits
+}
+
+/// A reader for `input` in `format`, or whatever format it looks like, with the decoder limits
+/// for `limits`. Fails if the header already says the image is too large.
+fn limited_reader<'a>(
+    input: &'a [u8],
+    format: Option<ImageFormat>,
+    limits: &DecodeLimits,
+) -> Result<Reader<Cursor<&'a [u8]>>, Error> {
+    let reader = || match format {
+        Some(format) => Ok(Reader::with_format(Cursor::new(input), format)),
+        None => Reader::new(Cursor::new(input))
+            .with_guessed_format()
+            .map_err(|e| Error::ImageDecode(e.into())),
+    };
+
+    // the header is enough to turn away anything too large before it's decoded
+    let (width, height) = reader()?.into_dimensions().map_err(Error::ImageDecode)?;
+    let pixels = u64::from(width) * u64::from(height);
+    if limits.max_width.is_some_and(|max| width > max)
+        || limits.max_height.is_some_and(|max| height > max)
+        || limits.max_pixels.is_some_and(|max| pixels > max)
+    {
+        return Err(too_large());
+    }
+
+    let mut reader = reader()?;
+    reader.limits(image_limits(limits));
+    Ok(reader)
+}
+
+/// Decodes `input` as `format` within `limits`, without touching its orientation, size or colors.
+pub(crate) fn decode_image(
+    input: &[u8],
+    format: ImageFormat,
+    limits: &DecodeLimits,
+) -> Result<DynamicImage, Error> {
+    let decoder = limited_reader(input, Some(format), limits)?
+        .into_decoder()
+        .map_err(Error::ImageDecode)?;
+    image_limits(limits)
+        .reserve(decoder.total_bytes())
+        .map_err(Error::ImageDecode)?;
+    DynamicImage::from_decoder(decoder).map_err(Error::ImageDecode)
+}
+
+/// Decodes `input` within `limits`, applies its EXIF orientation, scales it down if it's above
+/// `limits.downscale_above` and converts it to sRGB if it has a wide gamut profile.
+pub(crate) fn load_image(input: &[u8], limits: &DecodeLimits) -> Result<DynamicImage, Error> {
+    let reader = limited_reader(input, None, limits)?;
+    let format = reader.format();
+    let mut decoder = reader.into_decoder().map_err(Error::ImageDecode)?;
+    image_limits(limits)
+        .reserve(decoder.total_bytes())
+        .map_err(Error::ImageDecode)?;
+    // a profile that can't be read is no reason to fail, the image just stays as it is
+    let icc = decoder.icc_profile().ok().flatten();
+    let image = DynamicImage::from_decoder(decoder).map_err(Error::ImageDecode)?;
+    let image = apply_orientation(image, exif_orientation(input));
+
+    let conversion = match format {
+        Some(ImageFormat::Png) => profile::from_png_cicp(input),
+        _ => None,
+    }
+    .or_else(|| profile::from_icc(icc.as_deref()?));
+    let image = downscale(image, limits.downscale_above);
+    Ok(match conversion {
+        Some(conversion) => {
+            let mut rgba = image.into_rgba8();
+            conversion.apply(&mut rgba);
+            DynamicImage::ImageRgba8(rgba)
+        }
+        None => image,
+    })
+}
+
+/// The EXIF orientation tag, from 1 (upright) to 8.
+fn exif_orientation(input: &[u8]) -> u32 {
+    exif::Reader::new()
+        .read_from_container(&mut Cursor::new(input))
+        .ok()
+        .and_then(|exif| {
+            exif.get_field(Tag::Orientation, In::PRIMARY)?
+                .value
+                .get_uint(0)
+        })
+        .unwrap_or(1)
+}
+
+fn apply_orientation(image: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+fn downscale(image: DynamicImage, downscale_above: Option<u64>) -> DynamicImage {
+    let (width, height) = (image.width(), image.height());
+    let pixels = u64::from(width) * u64::from(height);
+
+    match downscale_above {
+        Some(max) if pixels > max.max(1) => {
+            #[allow(
+                clippy::cast_precision_loss,
+                clippy::cast_possible_truncation,
+                clippy::cast_sign_loss
+            )]
+            let (width, height) = {
+                let scale = (max as f64 / pixels as f64).sqrt();
+                (
+                    ((f64::from(width) * scale) as u32).max(1),
+                    ((f64::from(height) * scale) as u32).max(1),
+                )
+            };
+            image.resize_exact(width, height, FilterType::Triangle)
+        }
+        _ => image,
+    }
+}
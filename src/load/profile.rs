@@ -0,0 +1,337 @@
+#![allow(
+    clippy::many_single_char_names,
+    clippy::cast_precision_loss,
+    clippy::cast_possible_truncation,
+    clippy::cast_possible_wrap,
+    clippy::cast_sign_loss
+)]
+// the casts are between table indices and channel values, none of them get anywhere near the
+// limits of their types
+
+// converting wide gamut images to sRGB. this only knows matrix/TRC profiles, which is what Display
+// P3, Adobe RGB and friends are. anything fancier (LUT profiles, CMYK, HDR transfer functions)
+// gets left alone and treated as sRGB like before
+
+use image::RgbaImage;
+
+type Mat3 = [[f64; 3]; 3];
+
+// linear sRGB from XYZ relative to D50 (bradford adapted), which is where ICC profiles put their
+// primaries
+const XYZ_D50_TO_SRGB: Mat3 = [
+    [3.133_856_1, -1.616_866_7, -0.490_614_6],
+    [-0.978_768_4, 1.916_141_5, 0.033_454_0],
+    [0.071_945_3, -0.228_991_4, 1.405_242_7],
+];
+
+// linear sRGB from XYZ relative to D65, for cICP which gives primaries directly
+const XYZ_D65_TO_SRGB: Mat3 = [
+    [3.240_454_2, -1.537_138_5, -0.498_531_4],
+    [-0.969_266_0, 1.876_010_8, 0.041_556_0],
+    [0.055_643_4, -0.204_025_9, 1.057_225_2],
+];
+
+const D65: [f64; 2] = [0.3127, 0.3290];
+
+fn mul(a: &Mat3, b: &Mat3) -> Mat3 {
+    let mut out = [[0.0; 3]; 3];
+    for (i, row) in out.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    out
+}
+
+fn invert(m: &Mat3) -> Option<Mat3> {
+    let det = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+        - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+        + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+    if det.abs() < 1e-12 {
+        return None;
+    }
+
+    let mut out = [[0.0; 3]; 3];
+    for (i, row) in out.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            // cofactor of the transposed element
+            let (r0, r1) = ((j + 1) % 3, (j + 2) % 3);
+            let (c0, c1) = ((i + 1) % 3, (i + 2) % 3);
+            *value = (m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]) / det;
+        }
+    }
+    Some(out)
+}
+
+/// The matrix from linear RGB to XYZ for the given primaries and white point.
+fn primaries_to_xyz(primaries: [[f64; 2]; 3], white: [f64; 2]) -> Option<Mat3> {
+    let xyz = |[x, y]: [f64; 2]| [x / y, 1.0, (1.0 - x - y) / y];
+    let [r, g, b] = primaries.map(xyz);
+    let unscaled = [[r[0], g[0], b[0]], [r[1], g[1], b[1]], [r[2], g[2], b[2]]];
+
+    let w = xyz(white);
+    let inverse = invert(&unscaled)?;
+    let s: Vec<f64> = (0..3)
+        .map(|i| (0..3).map(|k| inverse[i][k] * w[k]).sum())
+        .collect();
+    Some(unscaled.map(|row| [row[0] * s[0], row[1] * s[1], row[2] * s[2]]))
+}
+
+/// A tone response curve, decoding an encoded value from 0 to 1 into linear light.
+#[derive(Clone)]
+enum Trc {
+    /// ICC parametric curve in its most general form: `(a * x + b) ^ g + e` from `d` up, and
+    /// `c * x + f` below.
+    Parametric([f64; 7]),
+    Table(Vec<f64>),
+}
+
+impl Trc {
+    const SRGB: Trc = Trc::Parametric([
+        2.4,
+        1.0 / 1.055,
+        0.055 / 1.055,
+        1.0 / 12.92,
+        0.040_45,
+        0.0,
+        0.0,
+    ]);
+
+    fn gamma(g: f64) -> Trc {
+        Trc::Parametric([g, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0])
+    }
+
+    fn eval(&self, x: f64) -> f64 {
+        match self {
+            Trc::Parametric([g, a, b, c, d, e, f]) => {
+                if x >= *d {
+                    (a * x + b).max(0.0).powf(*g) + e
+                } else {
+                    c * x + f
+                }
+            }
+            Trc::Table(table) => {
+                let pos = x.clamp(0.0, 1.0) * (table.len() - 1) as f64;
+                let i = (pos as usize).min(table.len() - 2);
+                let t = pos - i as f64;
+                table[i] + (table[i + 1] - table[i]) * t
+            }
+        }
+    }
+}
+
+fn srgb_encode(x: f64) -> f64 {
+    if x <= 0.003_130_8 {
+        12.92 * x
+    } else {
+        1.055 * x.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Everything needed to move an image's pixels into sRGB.
+pub(super) struct Conversion {
+    /// Linear light for every channel value, per channel.
+    decode: [[f64; 256]; 3],
+    /// Linear image RGB to linear sRGB.
+    matrix: Mat3,
+}
+
+// resolution of the table that encodes linear light back into sRGB bytes
+const ENCODE_STEPS: usize = 1 << 16;
+
+impl Conversion {
+    fn new(trc: &[Trc; 3], matrix: Mat3) -> Option<Conversion> {
+        let mut decode = [[0.0; 256]; 3];
+        for (table, trc) in decode.iter_mut().zip(trc) {
+            for (i, value) in table.iter_mut().enumerate() {
+                *value = trc.eval(i as f64 / 255.0);
+            }
+        }
+
+        // profiles that describe sRGB anyway aren't worth the rounding
+        let identity = (0..3)
+            .all(|i| (0..3).all(|j| (matrix[i][j] - f64::from(u8::from(i == j))).abs() < 2e-3));
+        let srgb = decode.iter().all(|table| {
+            table
+                .iter()
+                .enumerate()
+                .all(|(i, v)| (v - Trc::SRGB.eval(i as f64 / 255.0)).abs() < 1e-3)
+        });
+        (!(identity && srgb)).then_some(Conversion { decode, matrix })
+    }
+
+    pub(super) fn apply(&self, image: &mut RgbaImage) {
+        let encode: Vec<u8> = (0..ENCODE_STEPS)
+            .map(|i| (srgb_encode(i as f64 / (ENCODE_STEPS - 1) as f64) * 255.0).round() as u8)
+            .collect();
+        let m = &self.matrix;
+
+        for pixel in image.pixels_mut() {
+            let [r, g, b, _] = &mut pixel.0;
+            let lin = [
+                self.decode[0][*r as usize],
+                self.decode[1][*g as usize],
+                self.decode[2][*b as usize],
+            ];
+            let out = m.map(|row| {
+                let v = (row[0] * lin[0] + row[1] * lin[1] + row[2] * lin[2]).clamp(0.0, 1.0);
+                encode[(v * (ENCODE_STEPS - 1) as f64).round() as usize]
+            });
+            [*r, *g, *b] = out;
+        }
+    }
+}
+
+fn be_u32(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn be_u16(bytes: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(bytes.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn s15_fixed16(bytes: &[u8], at: usize) -> Option<f64> {
+    Some(f64::from(be_u32(bytes, at)? as i32) / 65536.0)
+}
+
+/// The conversion for an ICC profile, if it's an RGB matrix/TRC profile.
+pub(super) fn from_icc(icc: &[u8]) -> Option<Conversion> {
+    if icc.get(16..20)? != b"RGB " || icc.get(20..24)? != b"XYZ " {
+        return None;
+    }
+
+    let tag = |signature: &[u8; 4]| {
+        // the count comes from the file, so it's held to the entries that fit in it
+        let count = (be_u32(icc, 128)? as usize).min(icc.len().saturating_sub(132) / 12);
+        (0..count).find_map(|i| {
+            let entry = i.checked_mul(12)?.checked_add(132)?;
+            if icc.get(entry..entry + 4)? != signature {
+                return None;
+            }
+            let offset = be_u32(icc, entry + 4)? as usize;
+            let size = be_u32(icc, entry + 8)? as usize;
+            icc.get(offset..offset.checked_add(size)?)
+        })
+    };
+    let xyz = |signature: &[u8; 4]| {
+        let data = tag(signature)?;
+        (data.get(0..4)? == b"XYZ ").then_some(())?;
+        Some([
+            s15_fixed16(data, 8)?,
+            s15_fixed16(data, 12)?,
+            s15_fixed16(data, 16)?,
+        ])
+    };
+    let trc = |signature: &[u8; 4]| parse_trc(tag(signature)?);
+
+    let [r, g, b] = [xyz(b"rXYZ")?, xyz(b"gXYZ")?, xyz(b"bXYZ")?];
+    let to_xyz = [[r[0], g[0], b[0]], [r[1], g[1], b[1]], [r[2], g[2], b[2]]];
+    let trc = [trc(b"rTRC")?, trc(b"gTRC")?, trc(b"bTRC")?];
+
+    Conversion::new(&trc, mul(&XYZ_D50_TO_SRGB, &to_xyz))
+}
+
+fn parse_trc(data: &[u8]) -> Option<Trc> {
+    match data.get(0..4)? {
+        b"curv" => match be_u32(data, 8)? {
+            0 => Some(Trc::gamma(1.0)),
+            1 => Some(Trc::gamma(f64::from(be_u16(data, 12)?) / 256.0)),
+            n => {
+                let table = (0..n as usize)
+                    .map(|i| Some(f64::from(be_u16(data, 12 + i * 2)?) / 65535.0))
+                    .collect::<Option<Vec<_>>>()?;
+                Some(Trc::Table(table))
+            }
+        },
+        b"para" => {
+            let param = |i: usize| s15_fixed16(data, 12 + i * 4);
+            let g = param(0)?;
+            match be_u16(data, 8)? {
+                0 => Some(Trc::gamma(g)),
+                1 => {
+                    let (a, b) = (param(1)?, param(2)?);
+                    Some(Trc::Parametric([g, a, b, 0.0, -b / a, 0.0, 0.0]))
+                }
+                2 => {
+                    let (a, b, c) = (param(1)?, param(2)?, param(3)?);
+                    Some(Trc::Parametric([g, a, b, 0.0, -b / a, c, c]))
+                }
+                3 => Some(Trc::Parametric([
+                    g,
+                    param(1)?,
+                    param(2)?,
+                    param(3)?,
+                    param(4)?,
+                    0.0,
+                    0.0,
+                ])),
+                4 => Some(Trc::Parametric([
+                    g,
+                    param(1)?,
+                    param(2)?,
+                    param(3)?,
+                    param(4)?,
+                    param(5)?,
+                    param(6)?,
+                ])),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// The conversion for a PNG's `cICP` chunk, which takes precedence over its ICC profile. Only
+/// knows the SDR transfer functions, and the BT.709, Display P3 and BT.2020 primaries.
+pub(super) fn from_png_cicp(png: &[u8]) -> Option<Conversion> {
+    let mut at = 8;
+    let cicp = loop {
+        let len = be_u32(png, at)? as usize;
+        match png.get(at + 4..at + 8)? {
+            b"cICP" => break png.get(at + 8..at + 12)?,
+            b"IDAT" => return None,
+            _ => at = at.checked_add(len.checked_add(12)?).filter(|&at| at < png.len())?,
+        }
+    };
+
+    let primaries = match cicp[0] {
+        1 => [[0.64, 0.33], [0.30, 0.60], [0.15, 0.06]],
+        9 => [[0.708, 0.292], [0.170, 0.797], [0.131, 0.046]],
+        12 => [[0.680, 0.320], [0.265, 0.690], [0.150, 0.060]],
+        _ => return None,
+    };
+    let trc = match cicp[1] {
+        // the BT.709 family is close enough to sRGB for a pixel art palette
+        1 | 6 | 13 | 14 | 15 => Trc::SRGB,
+        8 => Trc::gamma(1.0),
+        _ => return None,
+    };
+
+    let to_xyz = primaries_to_xyz(primaries, D65)?;
+    Conversion::new(
+        &[trc.clone(), trc.clone(), trc],
+        mul(&XYZ_D65_TO_SRGB, &to_xyz),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tag_counts_are_held_to_the_profile() {
+        let mut icc = vec![0; 132 + 12];
+        icc[16..20].copy_from_slice(b"RGB ");
+        icc[20..24].copy_from_slice(b"XYZ ");
+        icc[128..132].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(from_icc(&icc).is_none());
+    }
+
+    #[test]
+    fn chunk_lengths_past_the_end_stop_the_search() {
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png.extend_from_slice(&u32::MAX.to_be_bytes());
+        png.extend_from_slice(b"tEXt");
+        assert!(from_png_cicp(&png).is_none());
+    }
+}
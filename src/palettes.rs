@@ -0,0 +1,468 @@
+#![allow(clippy::module_name_repetitions)]
+
+// palettes the way artists keep them: in files from GIMP, Aseprite, Photoshop, Paint.NET and
+// Lospec. named `palettes` so it doesn't get confused with the `palette` crate
+
+use std::fmt;
+
+use crate::{color_to_hex, Color, Error};
+
+use wasm_bindgen::prelude::*;
+
+mod builtin;
+mod color;
+mod read;
+mod write;
+
+pub use self::builtin::{builtin_palettes, BuiltinPalette, BuiltinPaletteInfo};
+pub use self::color::{parse_color, parse_colors, ColorParseError};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[wasm_bindgen]
+pub enum PaletteFormat {
+    /// GIMP and Inkscape `.gpl`.
+    Gpl,
+    /// JASC-PAL `.pal`, as written by Paint Shop Pro and Aseprite.
+    JascPal,
+    /// Adobe Color Table `.act`.
+    Act,
+    /// Adobe Swatch Exchange `.ase`.
+    Ase,
+    /// Paint.NET `.txt`, one `AARRGGBB` per line.
+    PaintNet,
+    /// Lospec `.hex`, one `RRGGBB` per line, or `#RRGGBBAA` if some color needs alpha.
+    Hex,
+    /// Every distinct visible color of an image, in scan order.
+    Png,
+}
+
+impl PaletteFormat {
+    /// Guesses the format from the first bytes of a file. Color tables have no header, so a file
+    /// of their size is only taken as one if it doesn't read as a text palette.
+    #[must_use]
+    pub fn detect(bytes: &[u8]) -> Option<PaletteFormat> {
+        if bytes.starts_with(b"ASEF") {
+            return Some(PaletteFormat::Ase);
+        }
+        if bytes.starts_with(b"\x89PNG") {
+            return Some(PaletteFormat::Png);
+        }
+
+        let text = read::decode_text(bytes).and_then(Self::detect_text);
+        if bytes.len() == 768 || bytes.len() == 772 {
+            return text
+                .filter(|&format| parse_palette_as(bytes, format).is_ok())
+                .or(Some(PaletteFormat::Act));
+        }
+        text
+    }
+
+    fn detect_text(text: &str) -> Option<PaletteFormat> {
+        let mut lines = text.lines().map(str::trim).filter(|l| !l.is_empty());
+        let first = lines.clone().next()?;
+        if first.starts_with("GIMP Palette") {
+            Some(PaletteFormat::Gpl)
+        } else if first.starts_with("JASC-PAL") {
+            Some(PaletteFormat::JascPal)
+        } else if first.starts_with(';')
+            || lines.all(|l| l.len() == 8 && l.chars().all(|c| c.is_ascii_hexdigit()))
+        {
+            Some(PaletteFormat::PaintNet)
+        } else {
+            Some(PaletteFormat::Hex)
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            PaletteFormat::Gpl => "GIMP palette",
+            PaletteFormat::JascPal => "JASC-PAL palette",
+            PaletteFormat::Act => "Adobe Color Table",
+            PaletteFormat::Ase => "Adobe Swatch Exchange file",
+            PaletteFormat::PaintNet => "Paint.NET palette",
+            PaletteFormat::Hex => "hex palette",
+            PaletteFormat::Png => "swatch image",
+        }
+    }
+}
+
+/// A palette color, along with its name if the file it came from had one.
+#[wasm_bindgen]
+#[derive(Clone, PartialEq, Debug)]
+pub struct PaletteEntry {
+    #[wasm_bindgen(skip)]
+    pub color: Color,
+    #[wasm_bindgen(skip)]
+    pub name: Option<String>,
+}
+
+#[wasm_bindgen]
+impl PaletteEntry {
+    /// Takes any color `parse_color` understands.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the color can't be parsed.
+    #[wasm_bindgen(constructor)]
+    pub fn new(color: &str, name: Option<String>) -> Result<PaletteEntry, Error> {
+        let color = parse_colors(&[color])?[0];
+        Ok(PaletteEntry { color, name })
+    }
+
+    /// The color as `#RRGGBBAA`, ready for `I2PState::palette`.
+    #[wasm_bindgen(getter)]
+    #[must_use]
+    pub fn hex(&self) -> String {
+        color_to_hex(&self.color)
+    }
+
+    #[wasm_bindgen(getter)]
+    #[must_use]
+    pub fn name(&self) -> Option<String> {
+        self.name.clone()
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum PaletteError {
+    /// None of the supported formats looks like the file.
+    UnknownFormat,
+    /// A text format didn't start with its header.
+    MissingHeader { format: PaletteFormat },
+    /// A line of a text format that isn't a color. `line` counts from 1.
+    InvalidLine {
+        format: PaletteFormat,
+        line: usize,
+        text: String,
+    },
+    /// A binary format, or a text format's header, that doesn't add up.
+    Malformed {
+        format: PaletteFormat,
+        reason: String,
+    },
+    /// The file is fine but doesn't contain a single color, or there's nothing to write.
+    Empty { format: PaletteFormat },
+    /// The palette doesn't fit into the format.
+    TooManyColors { format: PaletteFormat, max: usize },
+    Encode {
+        format: PaletteFormat,
+        reason: String,
+    },
+}
+
+impl fmt::Display for PaletteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PaletteError::UnknownFormat => write!(f, "unrecognized palette format"),
+            PaletteError::MissingHeader { format } => {
+                write!(f, "not a {}: missing header", format.name())
+            }
+            PaletteError::InvalidLine { format, line, text } => {
+                write!(f, "invalid color on line {line} of {}: {text:?}", format.name())
+            }
+            PaletteError::Malformed { format, reason } => {
+                write!(f, "malformed {}: {reason}", format.name())
+            }
+            PaletteError::Empty { format } => write!(f, "{} has no colors", format.name()),
+            PaletteError::TooManyColors { format, max } => {
+                write!(f, "{} can't hold more than {max} colors", format.name())
+            }
+            PaletteError::Encode { format, reason } => {
+                write!(f, "can't write {}: {reason}", format.name())
+            }
+        }
+    }
+}
+
+impl std::error::Error for PaletteError {}
+
+impl From<Color> for PaletteEntry {
+    fn from(color: Color) -> Self {
+        PaletteEntry { color, name: None }
+    }
+}
+
+/// Reads a palette file, detecting its format from the contents.
+///
+/// # Errors
+///
+/// This function will return an error if the format can't be detected, or the file is malformed.
+pub fn parse_palette(bytes: &[u8]) -> Result<Vec<PaletteEntry>, PaletteError> {
+    let format = PaletteFormat::detect(bytes).ok_or(PaletteError::UnknownFormat)?;
+    parse_palette_as(bytes, format)
+}
+
+/// Reads a palette file of the given format.
+///
+/// # Errors
+///
+/// This function will return an error if the file is malformed or has no colors.
+pub fn parse_palette_as(
+    bytes: &[u8],
+    format: PaletteFormat,
+) -> Result<Vec<PaletteEntry>, PaletteError> {
+    let entries = match format {
+        PaletteFormat::Gpl => read::read_gpl(bytes)?,
+        PaletteFormat::JascPal => read::read_jasc_pal(bytes)?,
+        PaletteFormat::Act => read::read_act(bytes)?,
+        PaletteFormat::Ase => read::read_ase(bytes)?,
+        PaletteFormat::PaintNet => read::read_paint_net(bytes)?,
+        PaletteFormat::Hex => read::read_hex(bytes)?,
+        PaletteFormat::Png => read::read_png(bytes)?,
+    };
+
+    if entries.is_empty() {
+        return Err(PaletteError::Empty { format });
+    }
+    Ok(entries)
+}
+
+/// WASM-friendly wrapper for `parse_palette` and `parse_palette_as`. The format is detected from
+/// the contents when none is given.
+///
+/// # Errors
+///
+/// This function will return an error if the format can't be detected, or the file is malformed.
+#[wasm_bindgen]
+pub fn parse_palette_wasm(
+    bytes: &[u8],
+    format: Option<PaletteFormat>,
+) -> Result<Vec<PaletteEntry>, Error> {
+    let entries = match format {
+        Some(format) => parse_palette_as(bytes, format)?,
+        None => parse_palette(bytes)?,
+    };
+    Ok(entries)
+}
+
+/// Writes a palette file. Formats without names drop them, and formats without alpha drop that.
+///
+/// # Errors
+///
+/// This function will return an error if `entries` is empty, has more colors than the format
+/// holds, or can't be encoded.
+pub fn write_palette(
+    entries: &[PaletteEntry],
+    format: PaletteFormat,
+) -> Result<Vec<u8>, PaletteError> {
+    if entries.is_empty() {
+        return Err(PaletteError::Empty { format });
+    }
+
+    match format {
+        PaletteFormat::Gpl => Ok(write::write_gpl(entries)),
+        PaletteFormat::JascPal => Ok(write::write_jasc_pal(entries)),
+        PaletteFormat::Act => write::write_act(entries),
+        PaletteFormat::Ase => write::write_ase(entries),
+        PaletteFormat::PaintNet => Ok(write::write_paint_net(entries)),
+        PaletteFormat::Hex => Ok(write::write_hex(entries)),
+        PaletteFormat::Png => write::write_png(entries),
+    }
+}
+
+/// WASM-friendly wrapper for `write_palette`.
+///
+/// # Errors
+///
+/// This function will return an error if `entries` is empty, has more colors than the format
+/// holds, or can't be encoded.
+#[wasm_bindgen]
+#[allow(clippy::needless_pass_by_value)]
+pub fn write_palette_wasm(
+    entries: Vec<PaletteEntry>,
+    format: PaletteFormat,
+) -> Result<Vec<u8>, Error> {
+    Ok(write_palette(&entries, format)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn png_past_the_decode_limits_is_turned_away() {
+        // one pixel wider than the default limit, a few kilobytes once compressed
+        let entries: Vec<PaletteEntry> = (0..16385)
+            .map(|_| Color::new(1, 2, 3, 255).into())
+            .collect();
+        let png = write_palette(&entries, PaletteFormat::Png).unwrap();
+        assert!(matches!(
+            parse_palette_as(&png, PaletteFormat::Png),
+            Err(PaletteError::Malformed {
+                format: PaletteFormat::Png,
+                ..
+            })
+        ));
+    }
+
+    const FORMATS: [PaletteFormat; 7] = [
+        PaletteFormat::Gpl,
+        PaletteFormat::JascPal,
+        PaletteFormat::Act,
+        PaletteFormat::Ase,
+        PaletteFormat::PaintNet,
+        PaletteFormat::Hex,
+        PaletteFormat::Png,
+    ];
+
+    fn entry(rgba: [u8; 4], name: Option<&str>) -> PaletteEntry {
+        let [r, g, b, a] = rgba;
+        PaletteEntry {
+            color: Color::new(r, g, b, a),
+            name: name.map(str::to_string),
+        }
+    }
+
+    fn opaque() -> Vec<PaletteEntry> {
+        vec![
+            entry([255, 0, 0, 255], Some("Red")),
+            entry([0, 128, 64, 255], None),
+            entry([10, 20, 30, 255], Some("Dark blue")),
+            entry([255, 255, 255, 255], Some("White")),
+        ]
+    }
+
+    fn keeps_names(format: PaletteFormat) -> bool {
+        matches!(format, PaletteFormat::Gpl | PaletteFormat::Ase)
+    }
+
+    fn without_names(entries: &[PaletteEntry]) -> Vec<PaletteEntry> {
+        entries.iter().map(|e| e.color.into()).collect()
+    }
+
+    #[test]
+    fn opaque_palettes_round_trip() {
+        for format in FORMATS {
+            let expected = if keeps_names(format) {
+                opaque()
+            } else {
+                without_names(&opaque())
+            };
+            let bytes = write_palette(&opaque(), format).unwrap();
+            assert_eq!(PaletteFormat::detect(&bytes), Some(format));
+            assert_eq!(parse_palette(&bytes).unwrap(), expected, "{format:?}");
+        }
+    }
+
+    #[test]
+    fn alpha_round_trips_where_the_format_has_it() {
+        let entries = vec![
+            entry([255, 0, 0, 255], Some("Red")),
+            entry([0, 128, 64, 128], Some("Glass")),
+            entry([10, 20, 30, 1], None),
+        ];
+        for format in [
+            PaletteFormat::Gpl,
+            PaletteFormat::JascPal,
+            PaletteFormat::PaintNet,
+            PaletteFormat::Hex,
+            PaletteFormat::Png,
+        ] {
+            let expected = if keeps_names(format) {
+                entries.clone()
+            } else {
+                without_names(&entries)
+            };
+            let bytes = write_palette(&entries, format).unwrap();
+            assert_eq!(PaletteFormat::detect(&bytes), Some(format));
+            assert_eq!(parse_palette(&bytes).unwrap(), expected, "{format:?}");
+        }
+
+        // the color table only marks a single fully transparent entry
+        let mut entries = without_names(&opaque());
+        entries[2].color.alpha = 0;
+        let bytes = write_palette(&entries, PaletteFormat::Act).unwrap();
+        assert_eq!(parse_palette(&bytes).unwrap(), entries);
+    }
+
+    #[test]
+    fn hex_with_alpha_is_not_read_as_paint_net() {
+        let entries = [Color::new(0x11, 0x22, 0x33, 0x44).into()];
+        let bytes = write_palette(&entries, PaletteFormat::Hex).unwrap();
+        assert_eq!(bytes, b"#11223344\n");
+        assert_eq!(parse_palette(&bytes).unwrap(), entries);
+    }
+
+    #[test]
+    fn text_palettes_of_color_table_size_are_read_as_text() {
+        for format in [
+            PaletteFormat::Gpl,
+            PaletteFormat::JascPal,
+            PaletteFormat::PaintNet,
+            PaletteFormat::Hex,
+        ] {
+            for len in [768, 772] {
+                let mut bytes = write_palette(&opaque(), format).unwrap();
+                bytes.resize(len, b'\n');
+                assert_eq!(PaletteFormat::detect(&bytes), Some(format), "{format:?} {len}");
+            }
+        }
+
+        // 96 lines of `rrggbb` with Windows line breaks
+        let bytes = "00ff80\r\n".repeat(96).into_bytes();
+        assert_eq!(bytes.len(), 768);
+        assert_eq!(parse_palette(&bytes).unwrap().len(), 96);
+
+        // an all black table is valid UTF-8, but not a text palette
+        let black = vec![Color::new(0, 0, 0, 255).into(); 256];
+        let bytes = write_palette(&black, PaletteFormat::Act).unwrap();
+        assert_eq!(PaletteFormat::detect(&bytes), Some(PaletteFormat::Act));
+        assert_eq!(PaletteFormat::detect(&bytes[..768]), Some(PaletteFormat::Act));
+    }
+
+    #[test]
+    fn malformed_files_are_errors() {
+        let cases: [(PaletteFormat, &[u8]); 23] = [
+            (PaletteFormat::Gpl, b"Hello\n1 2 3\n"),
+            (PaletteFormat::Gpl, b"GIMP Palette\n1 2\n"),
+            (PaletteFormat::Gpl, b"GIMP Palette\n256 0 0\n"),
+            (PaletteFormat::Gpl, b"GIMP Palette\n1 2 3\n\xff\xfe\n"),
+            (PaletteFormat::Gpl, b"GIMP Palette\n#\n"),
+            (PaletteFormat::JascPal, b"JASC-PAL\r\n"),
+            (PaletteFormat::JascPal, b"JASC-PAL\r\n0200\r\n1\r\n1 2 3\r\n"),
+            (PaletteFormat::JascPal, b"JASC-PAL\r\n0100\r\nmany\r\n"),
+            (PaletteFormat::JascPal, b"JASC-PAL\r\n0100\r\n2\r\n1 2 3\r\n"),
+            (PaletteFormat::JascPal, b"JASC-PAL\r\n0100\r\n1\r\n1 2 3 4 5\r\n"),
+            (PaletteFormat::Act, b""),
+            (PaletteFormat::Act, &[0; 771]),
+            (PaletteFormat::Act, &[0; 772]),
+            (PaletteFormat::Ase, b"ASE"),
+            (PaletteFormat::Ase, b"ASEF\0\x01\0\0\0\0\0\x01\0\x01\xff\xff\xff\xff"),
+            (PaletteFormat::Ase, b"ASEF\0\x01\0\0\0\0\0\x01\0\x01\0\0\0\x08\0\x01\0\0XYZ "),
+            (PaletteFormat::PaintNet, b";paint.net\nFF00112\n"),
+            (PaletteFormat::PaintNet, b"GGGGGGGG\n"),
+            (PaletteFormat::Hex, b"12345\n"),
+            (PaletteFormat::Hex, b"ff00ff\nnot a color\n"),
+            (PaletteFormat::Hex, b"\n\n"),
+            (PaletteFormat::Png, b"\x89PNG\r\n\x1a\n"),
+            (PaletteFormat::Png, b"\x89PNG\r\n\x1a\nnot really a png at all"),
+        ];
+        for (format, bytes) in cases {
+            assert!(
+                matches!(parse_palette_wasm(bytes, Some(format)), Err(Error::PaletteFile(_))),
+                "{format:?} {:?}",
+                String::from_utf8_lossy(bytes)
+            );
+        }
+    }
+
+    #[test]
+    fn truncated_files_are_errors_or_shorter_palettes() {
+        for format in FORMATS {
+            let bytes = write_palette(&opaque(), format).unwrap();
+            for len in 0..bytes.len() {
+                let truncated = parse_palette_as(&bytes[..len], format);
+                let Ok(entries) = truncated else { continue };
+                match format {
+                    // without its count footer the color table is read as all 256 slots
+                    PaletteFormat::Act => assert_eq!((len, entries.len()), (768, 256)),
+                    // the decoder stops once it has the pixels, so only the end marker can go
+                    PaletteFormat::Png => assert_eq!(entries, without_names(&opaque())),
+                    PaletteFormat::Ase => panic!("swatch file cut to {len} bytes was read"),
+                    // text formats can lose whole lines and still be fine
+                    _ => assert!(entries.len() <= opaque().len()),
+                }
+            }
+        }
+    }
+}
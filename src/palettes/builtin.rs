@@ -0,0 +1,534 @@
+// colors read best as plain 0xRRGGBB
+#![allow(clippy::unreadable_literal)]
+
+use crate::{color_to_hex, Color};
+
+use super::PaletteEntry;
+
+use wasm_bindgen::prelude::*;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[wasm_bindgen]
+pub enum BuiltinPalette {
+    RPlace2017,
+    /// The first 16 colors of r/place 2022.
+    RPlace2022Phase1,
+    /// 24 colors, after the first expansion of r/place 2022.
+    RPlace2022Phase2,
+    /// The final 32 colors of r/place 2022.
+    RPlace2022,
+    /// r/place 2023 ended on the same 32 colors as 2022.
+    RPlace2023,
+    Pico8,
+    Nes,
+    /// The four greens of the original Game Boy screen.
+    GameBoy,
+    Cga,
+    Ega,
+    C64,
+    Sweetie16,
+    Endesga32,
+    Slso8,
+    Oil6,
+}
+
+impl BuiltinPalette {
+    pub const ALL: [BuiltinPalette; 15] = [
+        BuiltinPalette::RPlace2017,
+        BuiltinPalette::RPlace2022Phase1,
+        BuiltinPalette::RPlace2022Phase2,
+        BuiltinPalette::RPlace2022,
+        BuiltinPalette::RPlace2023,
+        BuiltinPalette::Pico8,
+        BuiltinPalette::Nes,
+        BuiltinPalette::GameBoy,
+        BuiltinPalette::Cga,
+        BuiltinPalette::Ega,
+        BuiltinPalette::C64,
+        BuiltinPalette::Sweetie16,
+        BuiltinPalette::Endesga32,
+        BuiltinPalette::Slso8,
+        BuiltinPalette::Oil6,
+    ];
+
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            BuiltinPalette::RPlace2017 => "r/place 2017",
+            BuiltinPalette::RPlace2022Phase1 => "r/place 2022 (16 colors)",
+            BuiltinPalette::RPlace2022Phase2 => "r/place 2022 (24 colors)",
+            BuiltinPalette::RPlace2022 => "r/place 2022",
+            BuiltinPalette::RPlace2023 => "r/place 2023",
+            BuiltinPalette::Pico8 => "PICO-8",
+            BuiltinPalette::Nes => "NES",
+            BuiltinPalette::GameBoy => "Game Boy DMG",
+            BuiltinPalette::Cga => "CGA",
+            BuiltinPalette::Ega => "EGA",
+            BuiltinPalette::C64 => "Commodore 64",
+            BuiltinPalette::Sweetie16 => "Sweetie 16",
+            BuiltinPalette::Endesga32 => "Endesga 32",
+            BuiltinPalette::Slso8 => "SLSO8",
+            BuiltinPalette::Oil6 => "Oil 6",
+        }
+    }
+
+    fn table(self) -> &'static [(u32, &'static str)] {
+        match self {
+            BuiltinPalette::RPlace2017 => RPLACE_2017,
+            BuiltinPalette::RPlace2022Phase1 => RPLACE_2022_16,
+            BuiltinPalette::RPlace2022Phase2 => RPLACE_2022_24,
+            BuiltinPalette::RPlace2022 | BuiltinPalette::RPlace2023 => RPLACE_2022_32,
+            BuiltinPalette::Pico8 => PICO_8,
+            BuiltinPalette::Nes => NES,
+            BuiltinPalette::GameBoy => GAME_BOY,
+            BuiltinPalette::Cga => CGA,
+            BuiltinPalette::Ega => EGA,
+            BuiltinPalette::C64 => C64,
+            BuiltinPalette::Sweetie16 => SWEETIE_16,
+            BuiltinPalette::Endesga32 => ENDESGA_32,
+            BuiltinPalette::Slso8 => SLSO8,
+            BuiltinPalette::Oil6 => OIL_6,
+        }
+    }
+
+    #[must_use]
+    pub fn colors(self) -> Vec<Color> {
+        self.table()
+            .iter()
+            .map(|(rgb, _)| {
+                let [_, r, g, b] = rgb.to_be_bytes();
+                Color::new(r, g, b, 255)
+            })
+            .collect()
+    }
+
+    /// The colors as `#RRGGBBAA`, the way `I2PState::palette` and `process_image` take them.
+    #[must_use]
+    pub fn hex(self) -> Vec<String> {
+        self.colors().iter().map(color_to_hex).collect()
+    }
+
+    /// The colors along with their names, for the palettes that have official ones.
+    #[must_use]
+    pub fn entries(self) -> Vec<PaletteEntry> {
+        self.colors()
+            .into_iter()
+            .zip(self.table())
+            .map(|(color, (_, name))| PaletteEntry {
+                color,
+                name: (!name.is_empty()).then(|| (*name).to_string()),
+            })
+            .collect()
+    }
+}
+
+/// A built-in palette as handed out to JS, which can't call methods on enums.
+#[wasm_bindgen]
+#[derive(Clone, Copy)]
+pub struct BuiltinPaletteInfo {
+    palette: BuiltinPalette,
+}
+
+#[wasm_bindgen]
+impl BuiltinPaletteInfo {
+    #[wasm_bindgen(getter)]
+    #[must_use]
+    pub fn palette(&self) -> BuiltinPalette {
+        self.palette
+    }
+
+    #[wasm_bindgen(getter)]
+    #[must_use]
+    pub fn name(&self) -> String {
+        self.palette.name().to_string()
+    }
+
+    #[wasm_bindgen(getter)]
+    #[must_use]
+    pub fn colors(&self) -> Vec<String> {
+        self.palette.hex()
+    }
+
+    #[wasm_bindgen(getter)]
+    #[must_use]
+    pub fn entries(&self) -> Vec<PaletteEntry> {
+        self.palette.entries()
+    }
+}
+
+/// Lists every built-in palette with its name and colors.
+#[wasm_bindgen]
+#[must_use]
+pub fn builtin_palettes() -> Vec<BuiltinPaletteInfo> {
+    BuiltinPalette::ALL
+        .iter()
+        .map(|palette| BuiltinPaletteInfo { palette: *palette })
+        .collect()
+}
+
+const RPLACE_2017: &[(u32, &str)] = &[
+    (0xFFFFFF, "White"),
+    (0xE4E4E4, "Light Gray"),
+    (0x888888, "Gray"),
+    (0x222222, "Black"),
+    (0xFFA7D1, "Pink"),
+    (0xE50000, "Red"),
+    (0xE59500, "Orange"),
+    (0xA06A42, "Brown"),
+    (0xE5D900, "Yellow"),
+    (0x94E044, "Light Green"),
+    (0x02BE01, "Green"),
+    (0x00D3DD, "Cyan"),
+    (0x0083C7, "Blue"),
+    (0x0000EA, "Dark Blue"),
+    (0xCF6EE4, "Magenta"),
+    (0x820080, "Purple"),
+];
+
+// the 2022 canvas started with 16 colors and got 8 more in each of its two expansions
+const RPLACE_2022_16: &[(u32, &str)] = &[
+    (0xFF4500, "Red"),
+    (0xFFA800, "Orange"),
+    (0xFFD635, "Yellow"),
+    (0x00A368, "Dark Green"),
+    (0x7EED56, "Light Green"),
+    (0x2450A4, "Dark Blue"),
+    (0x3690EA, "Blue"),
+    (0x51E9F4, "Light Blue"),
+    (0x811E9F, "Dark Purple"),
+    (0xB44AC0, "Purple"),
+    (0xFF99AA, "Light Pink"),
+    (0x9C6926, "Brown"),
+    (0x000000, "Black"),
+    (0x898D90, "Gray"),
+    (0xD4D7D9, "Light Gray"),
+    (0xFFFFFF, "White"),
+];
+
+const RPLACE_2022_24: &[(u32, &str)] = &[
+    (0xBE0039, "Dark Red"),
+    (0xFF4500, "Red"),
+    (0xFFA800, "Orange"),
+    (0xFFD635, "Yellow"),
+    (0x00A368, "Dark Green"),
+    (0x00CC78, "Green"),
+    (0x7EED56, "Light Green"),
+    (0x00756F, "Dark Teal"),
+    (0x009EAA, "Teal"),
+    (0x2450A4, "Dark Blue"),
+    (0x3690EA, "Blue"),
+    (0x51E9F4, "Light Blue"),
+    (0x493AC1, "Indigo"),
+    (0x6A5CFF, "Periwinkle"),
+    (0x811E9F, "Dark Purple"),
+    (0xB44AC0, "Purple"),
+    (0xFF3881, "Pink"),
+    (0xFF99AA, "Light Pink"),
+    (0x6D482F, "Dark Brown"),
+    (0x9C6926, "Brown"),
+    (0x000000, "Black"),
+    (0x898D90, "Gray"),
+    (0xD4D7D9, "Light Gray"),
+    (0xFFFFFF, "White"),
+];
+
+const RPLACE_2022_32: &[(u32, &str)] = &[
+    (0x6D001A, "Burgundy"),
+    (0xBE0039, "Dark Red"),
+    (0xFF4500, "Red"),
+    (0xFFA800, "Orange"),
+    (0xFFD635, "Yellow"),
+    (0xFFF8B8, "Pale Yellow"),
+    (0x00A368, "Dark Green"),
+    (0x00CC78, "Green"),
+    (0x7EED56, "Light Green"),
+    (0x00756F, "Dark Teal"),
+    (0x009EAA, "Teal"),
+    (0x00CCC0, "Light Teal"),
+    (0x2450A4, "Dark Blue"),
+    (0x3690EA, "Blue"),
+    (0x51E9F4, "Light Blue"),
+    (0x493AC1, "Indigo"),
+    (0x6A5CFF, "Periwinkle"),
+    (0x94B3FF, "Lavender"),
+    (0x811E9F, "Dark Purple"),
+    (0xB44AC0, "Purple"),
+    (0xE4ABFF, "Pale Purple"),
+    (0xDE107F, "Magenta"),
+    (0xFF3881, "Pink"),
+    (0xFF99AA, "Light Pink"),
+    (0x6D482F, "Dark Brown"),
+    (0x9C6926, "Brown"),
+    (0xFFB470, "Beige"),
+    (0x000000, "Black"),
+    (0x515252, "Dark Gray"),
+    (0x898D90, "Gray"),
+    (0xD4D7D9, "Light Gray"),
+    (0xFFFFFF, "White"),
+];
+
+const PICO_8: &[(u32, &str)] = &[
+    (0x000000, "Black"),
+    (0x1D2B53, "Dark Blue"),
+    (0x7E2553, "Dark Purple"),
+    (0x008751, "Dark Green"),
+    (0xAB5236, "Brown"),
+    (0x5F574F, "Dark Grey"),
+    (0xC2C3C7, "Light Grey"),
+    (0xFFF1E8, "White"),
+    (0xFF004D, "Red"),
+    (0xFFA300, "Orange"),
+    (0xFFEC27, "Yellow"),
+    (0x00E436, "Green"),
+    (0x29ADFF, "Blue"),
+    (0x83769C, "Lavender"),
+    (0xFF77A8, "Pink"),
+    (0xFFCCAA, "Light Peach"),
+];
+
+// the 2C02 palette as most emulators show it, without the duplicate blacks
+const NES: &[(u32, &str)] = &[
+    (0x7C7C7C, ""),
+    (0x0000FC, ""),
+    (0x0000BC, ""),
+    (0x4428BC, ""),
+    (0x940084, ""),
+    (0xA80020, ""),
+    (0xA81000, ""),
+    (0x881400, ""),
+    (0x503000, ""),
+    (0x007800, ""),
+    (0x006800, ""),
+    (0x005800, ""),
+    (0x004058, ""),
+    (0x000000, ""),
+    (0xBCBCBC, ""),
+    (0x0078F8, ""),
+    (0x0058F8, ""),
+    (0x6844FC, ""),
+    (0xD800CC, ""),
+    (0xE40058, ""),
+    (0xF83800, ""),
+    (0xE45C10, ""),
+    (0xAC7C00, ""),
+    (0x00B800, ""),
+    (0x00A800, ""),
+    (0x00A844, ""),
+    (0x008888, ""),
+    (0xF8F8F8, ""),
+    (0x3CBCFC, ""),
+    (0x6888FC, ""),
+    (0x9878F8, ""),
+    (0xF878F8, ""),
+    (0xF85898, ""),
+    (0xF87858, ""),
+    (0xFCA044, ""),
+    (0xF8B800, ""),
+    (0xB8F818, ""),
+    (0x58D854, ""),
+    (0x58F898, ""),
+    (0x00E8D8, ""),
+    (0x787878, ""),
+    (0xFCFCFC, ""),
+    (0xA4E4FC, ""),
+    (0xB8B8F8, ""),
+    (0xD8B8F8, ""),
+    (0xF8B8F8, ""),
+    (0xF8A4C0, ""),
+    (0xF0D0B0, ""),
+    (0xFCE0A8, ""),
+    (0xF8D878, ""),
+    (0xD8F878, ""),
+    (0xB8F8B8, ""),
+    (0xB8F8D8, ""),
+    (0x00FCFC, ""),
+    (0xF8D8F8, ""),
+];
+
+const GAME_BOY: &[(u32, &str)] = &[
+    (0x0F380F, "Darkest"),
+    (0x306230, "Dark"),
+    (0x8BAC0F, "Light"),
+    (0x9BBC0F, "Lightest"),
+];
+
+const CGA: &[(u32, &str)] = &[
+    (0x000000, "Black"),
+    (0x0000AA, "Blue"),
+    (0x00AA00, "Green"),
+    (0x00AAAA, "Cyan"),
+    (0xAA0000, "Red"),
+    (0xAA00AA, "Magenta"),
+    (0xAA5500, "Brown"),
+    (0xAAAAAA, "Light Gray"),
+    (0x555555, "Dark Gray"),
+    (0x5555FF, "Light Blue"),
+    (0x55FF55, "Light Green"),
+    (0x55FFFF, "Light Cyan"),
+    (0xFF5555, "Light Red"),
+    (0xFF55FF, "Light Magenta"),
+    (0xFFFF55, "Yellow"),
+    (0xFFFFFF, "White"),
+];
+
+// every combination of 2 bits per channel, in hardware order
+const EGA: &[(u32, &str)] = &[
+    (0x000000, ""),
+    (0x0000AA, ""),
+    (0x00AA00, ""),
+    (0x00AAAA, ""),
+    (0xAA0000, ""),
+    (0xAA00AA, ""),
+    (0xAAAA00, ""),
+    (0xAAAAAA, ""),
+    (0x000055, ""),
+    (0x0000FF, ""),
+    (0x00AA55, ""),
+    (0x00AAFF, ""),
+    (0xAA0055, ""),
+    (0xAA00FF, ""),
+    (0xAAAA55, ""),
+    (0xAAAAFF, ""),
+    (0x005500, ""),
+    (0x0055AA, ""),
+    (0x00FF00, ""),
+    (0x00FFAA, ""),
+    (0xAA5500, ""),
+    (0xAA55AA, ""),
+    (0xAAFF00, ""),
+    (0xAAFFAA, ""),
+    (0x005555, ""),
+    (0x0055FF, ""),
+    (0x00FF55, ""),
+    (0x00FFFF, ""),
+    (0xAA5555, ""),
+    (0xAA55FF, ""),
+    (0xAAFF55, ""),
+    (0xAAFFFF, ""),
+    (0x550000, ""),
+    (0x5500AA, ""),
+    (0x55AA00, ""),
+    (0x55AAAA, ""),
+    (0xFF0000, ""),
+    (0xFF00AA, ""),
+    (0xFFAA00, ""),
+    (0xFFAAAA, ""),
+    (0x550055, ""),
+    (0x5500FF, ""),
+    (0x55AA55, ""),
+    (0x55AAFF, ""),
+    (0xFF0055, ""),
+    (0xFF00FF, ""),
+    (0xFFAA55, ""),
+    (0xFFAAFF, ""),
+    (0x555500, ""),
+    (0x5555AA, ""),
+    (0x55FF00, ""),
+    (0x55FFAA, ""),
+    (0xFF5500, ""),
+    (0xFF55AA, ""),
+    (0xFFFF00, ""),
+    (0xFFFFAA, ""),
+    (0x555555, ""),
+    (0x5555FF, ""),
+    (0x55FF55, ""),
+    (0x55FFFF, ""),
+    (0xFF5555, ""),
+    (0xFF55FF, ""),
+    (0xFFFF55, ""),
+    (0xFFFFFF, ""),
+];
+
+// pepto's measurements
+const C64: &[(u32, &str)] = &[
+    (0x000000, "Black"),
+    (0xFFFFFF, "White"),
+    (0x68372B, "Red"),
+    (0x70A4B2, "Cyan"),
+    (0x6F3D86, "Purple"),
+    (0x588D43, "Green"),
+    (0x352879, "Blue"),
+    (0xB8C76F, "Yellow"),
+    (0x6F4F25, "Orange"),
+    (0x433900, "Brown"),
+    (0x9A6759, "Light Red"),
+    (0x444444, "Dark Grey"),
+    (0x6C6C6C, "Grey"),
+    (0x9AD284, "Light Green"),
+    (0x6C5EB5, "Light Blue"),
+    (0x959595, "Light Grey"),
+];
+
+const SWEETIE_16: &[(u32, &str)] = &[
+    (0x1A1C2C, ""),
+    (0x5D275D, ""),
+    (0xB13E53, ""),
+    (0xEF7D57, ""),
+    (0xFFCD75, ""),
+    (0xA7F070, ""),
+    (0x38B764, ""),
+    (0x257179, ""),
+    (0x29366F, ""),
+    (0x3B5DC9, ""),
+    (0x41A6F6, ""),
+    (0x73EFF7, ""),
+    (0xF4F4F4, ""),
+    (0x94B0C2, ""),
+    (0x566C86, ""),
+    (0x333C57, ""),
+];
+
+const ENDESGA_32: &[(u32, &str)] = &[
+    (0xBE4A2F, ""),
+    (0xD77643, ""),
+    (0xEAD4AA, ""),
+    (0xE4A672, ""),
+    (0xB86F50, ""),
+    (0x733E39, ""),
+    (0x3E2731, ""),
+    (0xA22633, ""),
+    (0xE43B44, ""),
+    (0xF77622, ""),
+    (0xFEAE34, ""),
+    (0xFEE761, ""),
+    (0x63C74D, ""),
+    (0x3E8948, ""),
+    (0x265C42, ""),
+    (0x193C3E, ""),
+    (0x124E89, ""),
+    (0x0099DB, ""),
+    (0x2CE8F5, ""),
+    (0xFFFFFF, ""),
+    (0xC0CBDC, ""),
+    (0x8B9BB4, ""),
+    (0x5A6988, ""),
+    (0x3A4466, ""),
+    (0x262B44, ""),
+    (0x181425, ""),
+    (0xFF0044, ""),
+    (0x68386C, ""),
+    (0xB55088, ""),
+    (0xF6757A, ""),
+    (0xE8B796, ""),
+    (0xC28569, ""),
+];
+
+const SLSO8: &[(u32, &str)] = &[
+    (0x0D2B45, ""),
+    (0x203C56, ""),
+    (0x544E68, ""),
+    (0x8D697A, ""),
+    (0xD08159, ""),
+    (0xFFAA5E, ""),
+    (0xFFD4A3, ""),
+    (0xFFECD6, ""),
+];
+
+const OIL_6: &[(u32, &str)] = &[
+    (0xFBF5EF, ""),
+    (0xF2D3AB, ""),
+    (0xC69FA5, ""),
+    (0x8B6D9C, ""),
+    (0x494D7E, ""),
+    (0x272744, ""),
+];
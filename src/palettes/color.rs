@@ -0,0 +1,169 @@
+use std::fmt;
+
+use palette::{FromColor, Hsl, Srgb};
+
+use crate::Color;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ColorParseError {
+    /// Position of the offending entry in the list that was parsed.
+    pub index: usize,
+    pub text: String,
+}
+
+impl fmt::Display for ColorParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid color {:?} at palette index {}", self.text, self.index)
+    }
+}
+
+impl std::error::Error for ColorParseError {}
+
+/// Parses a color the way people paste them: `#RGB`, `#RGBA`, `#RRGGBB` and `#RRGGBBAA` with or
+/// without the `#`, CSS names, and CSS `rgb()`, `rgba()`, `hsl()` and `hsla()` in both the comma
+/// and the space separated syntax.
+#[must_use]
+pub fn parse_color(text: &str) -> Option<Color> {
+    let text = text.trim().to_ascii_lowercase();
+
+    if let Some(args) = function_args(&text, &["rgba", "rgb"]) {
+        let (channels, alpha) = split_args(args)?;
+        let [r, g, b] = channels;
+        return Some(Color::new(
+            parse_rgb_channel(r)?,
+            parse_rgb_channel(g)?,
+            parse_rgb_channel(b)?,
+            alpha.map_or(Some(255), parse_alpha)?,
+        ));
+    }
+
+    if let Some(args) = function_args(&text, &["hsla", "hsl"]) {
+        let (channels, alpha) = split_args(args)?;
+        let [h, s, l] = channels;
+        let hsl = Hsl::new(parse_hue(h)?, parse_fraction(s)?, parse_fraction(l)?);
+        let rgb: Srgb<u8> = Srgb::from_color(hsl).into_format();
+        return Some(Color::new(
+            rgb.red,
+            rgb.green,
+            rgb.blue,
+            alpha.map_or(Some(255), parse_alpha)?,
+        ));
+    }
+
+    if text == "transparent" {
+        return Some(Color::new(0, 0, 0, 0));
+    }
+    if let Some(rgb) = palette::named::from_str(&text) {
+        return Some(Color::new(rgb.red, rgb.green, rgb.blue, 255));
+    }
+
+    parse_hex(text.strip_prefix('#').unwrap_or(&text))
+}
+
+/// Parses every entry with `parse_color`, failing on the first one that isn't a color.
+///
+/// # Errors
+///
+/// This function will return an error naming the index and text of the first entry that can't be
+/// parsed.
+pub fn parse_colors<S: AsRef<str>>(entries: &[S]) -> Result<Vec<Color>, ColorParseError> {
+    entries
+        .iter()
+        .enumerate()
+        .map(|(index, text)| {
+            parse_color(text.as_ref()).ok_or_else(|| ColorParseError {
+                index,
+                text: text.as_ref().to_string(),
+            })
+        })
+        .collect()
+}
+
+fn parse_hex(digits: &str) -> Option<Color> {
+    if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let value = u32::from_str_radix(digits, 16).ok()?;
+    // short forms repeat every digit, 0xf becomes 0xff
+    #[allow(clippy::cast_possible_truncation)]
+    let nibble = |shift: u32| ((value >> shift) & 0xF) as u8 * 17;
+    match digits.len() {
+        3 => Some(Color::new(nibble(8), nibble(4), nibble(0), 255)),
+        4 => Some(Color::new(nibble(12), nibble(8), nibble(4), nibble(0))),
+        6 => {
+            let [_, r, g, b] = value.to_be_bytes();
+            Some(Color::new(r, g, b, 255))
+        }
+        8 => {
+            let [r, g, b, a] = value.to_be_bytes();
+            Some(Color::new(r, g, b, a))
+        }
+        _ => None,
+    }
+}
+
+/// The text between the parentheses of `name(...)`, for the first name that matches.
+fn function_args<'a>(text: &'a str, names: &[&str]) -> Option<&'a str> {
+    names.iter().find_map(|name| {
+        text.strip_prefix(name)?
+            .trim_start()
+            .strip_prefix('(')?
+            .strip_suffix(')')
+    })
+}
+
+/// Splits `a, b, c[, alpha]` or `a b c[ / alpha]` into the three channels and the alpha.
+fn split_args(args: &str) -> Option<([&str; 3], Option<&str>)> {
+    let parts: Vec<&str> = if args.contains(',') {
+        args.split(',').map(str::trim).collect()
+    } else {
+        let (channels, alpha) = match args.split_once('/') {
+            Some((channels, alpha)) => (channels, Some(alpha.trim())),
+            None => (args, None),
+        };
+        channels.split_whitespace().chain(alpha).collect()
+    };
+
+    match parts[..] {
+        [a, b, c] => Some(([a, b, c], None)),
+        [a, b, c, alpha] => Some(([a, b, c], Some(alpha))),
+        _ => None,
+    }
+}
+
+/// A number, or a percentage of `scale`.
+fn parse_number(text: &str, scale: f32) -> Option<f32> {
+    let value = match text.strip_suffix('%') {
+        Some(percent) => percent.trim().parse::<f32>().ok()? / 100.0 * scale,
+        None => text.parse().ok()?,
+    };
+    value.is_finite().then_some(value)
+}
+
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn parse_rgb_channel(text: &str) -> Option<u8> {
+    Some(parse_number(text, 255.0)?.round().clamp(0.0, 255.0) as u8)
+}
+
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn parse_alpha(text: &str) -> Option<u8> {
+    Some((parse_number(text, 1.0)?.clamp(0.0, 1.0) * 255.0).round() as u8)
+}
+
+/// Saturation and lightness, as a percentage or a bare number of percent.
+fn parse_fraction(text: &str) -> Option<f32> {
+    let percent = text.strip_suffix('%').unwrap_or(text).trim();
+    Some((percent.parse::<f32>().ok()? / 100.0).clamp(0.0, 1.0))
+}
+
+/// A hue in degrees, with an optional CSS angle unit.
+fn parse_hue(text: &str) -> Option<f32> {
+    let units = [("deg", 1.0), ("grad", 0.9), ("rad", 180.0 / std::f32::consts::PI), ("turn", 360.0)];
+    let (number, scale) = units
+        .iter()
+        .find_map(|(unit, scale)| text.strip_suffix(unit).map(|n| (n, *scale)))
+        .unwrap_or((text, 1.0));
+    let degrees = number.trim().parse::<f32>().ok()? * scale;
+    degrees.is_finite().then_some(degrees)
+}
@@ -0,0 +1,350 @@
+use std::collections::HashSet;
+
+use image::{GenericImageView, ImageFormat};
+use palette::{chromatic_adaptation::AdaptFrom, white_point::D50, Lab, Srgb};
+
+use crate::{load::decode_image, Color, DecodeLimits};
+
+use super::{PaletteEntry, PaletteError, PaletteFormat};
+
+/// The file as UTF-8 text, without a byte order mark.
+pub(super) fn decode_text(bytes: &[u8]) -> Option<&str> {
+    let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+    std::str::from_utf8(bytes).ok()
+}
+
+fn text(bytes: &[u8], format: PaletteFormat) -> Result<&str, PaletteError> {
+    decode_text(bytes).ok_or_else(|| PaletteError::Malformed {
+        format,
+        reason: "not UTF-8 text".to_string(),
+    })
+}
+
+/// Trimmed non-empty lines, numbered from 1.
+fn numbered_lines(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty())
+}
+
+/// Reads `n` whitespace separated channel values off the front of `text`, and returns them along
+/// with whatever follows.
+fn take_channels(text: &str, n: usize) -> Option<([u8; 4], &str)> {
+    let mut channels = [255; 4];
+    let mut rest = text;
+    for channel in channels.iter_mut().take(n) {
+        rest = rest.trim_start();
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        *channel = rest[..end].parse().ok()?;
+        rest = &rest[end..];
+    }
+    Some((channels, rest.trim()))
+}
+
+fn parse_hex(digits: &str) -> Option<u32> {
+    if digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        u32::from_str_radix(digits, 16).ok()
+    } else {
+        None
+    }
+}
+
+fn entry([r, g, b, a]: [u8; 4], name: Option<String>) -> PaletteEntry {
+    PaletteEntry {
+        color: Color::new(r, g, b, a),
+        name,
+    }
+}
+
+pub(super) fn read_gpl(bytes: &[u8]) -> Result<Vec<PaletteEntry>, PaletteError> {
+    let format = PaletteFormat::Gpl;
+    let mut lines = numbered_lines(text(bytes, format)?);
+    if !lines.next().is_some_and(|(_, l)| l.starts_with("GIMP Palette")) {
+        return Err(PaletteError::MissingHeader { format });
+    }
+
+    // gimp 3 can store alpha, announced with a `Channels: RGBA` header
+    let mut channels = 3;
+    let mut entries = Vec::new();
+    for (line, text) in lines {
+        if text.starts_with('#') || text.starts_with("Name:") || text.starts_with("Columns:") {
+            continue;
+        }
+        if let Some(value) = text.strip_prefix("Channels:") {
+            channels = if value.trim() == "RGBA" { 4 } else { 3 };
+            continue;
+        }
+
+        let (color, name) = take_channels(text, channels).ok_or_else(|| {
+            PaletteError::InvalidLine {
+                format,
+                line,
+                text: text.to_string(),
+            }
+        })?;
+        entries.push(entry(color, (!name.is_empty()).then(|| name.to_string())));
+    }
+    Ok(entries)
+}
+
+pub(super) fn read_jasc_pal(bytes: &[u8]) -> Result<Vec<PaletteEntry>, PaletteError> {
+    let format = PaletteFormat::JascPal;
+    let mut lines = numbered_lines(text(bytes, format)?);
+    if !lines.next().is_some_and(|(_, l)| l == "JASC-PAL") {
+        return Err(PaletteError::MissingHeader { format });
+    }
+    let malformed = |reason: String| PaletteError::Malformed { format, reason };
+
+    match lines.next() {
+        Some((_, "0100")) => {}
+        Some((_, version)) => return Err(malformed(format!("unsupported version {version:?}"))),
+        None => return Err(malformed("missing version".to_string())),
+    }
+    let count: usize = match lines.next() {
+        Some((_, count)) => count
+            .parse()
+            .map_err(|_| malformed(format!("invalid color count {count:?}")))?,
+        None => return Err(malformed("missing color count".to_string())),
+    };
+
+    let mut entries = Vec::with_capacity(count);
+    for (line, text) in lines {
+        let color = take_channels(text, 4)
+            .or_else(|| take_channels(text, 3))
+            .filter(|(_, rest)| rest.is_empty())
+            .ok_or_else(|| PaletteError::InvalidLine {
+                format,
+                line,
+                text: text.to_string(),
+            })?
+            .0;
+        entries.push(entry(color, None));
+    }
+
+    if entries.len() != count {
+        return Err(malformed(format!(
+            "header promises {count} colors, found {}",
+            entries.len()
+        )));
+    }
+    Ok(entries)
+}
+
+/// 256 RGB triplets, optionally followed by the number of colors in use and the index of the
+/// transparent one, both big endian `u16`s.
+pub(super) fn read_act(bytes: &[u8]) -> Result<Vec<PaletteEntry>, PaletteError> {
+    let format = PaletteFormat::Act;
+    let (count, transparent) = match bytes.len() {
+        768 => (256, None),
+        772 => {
+            let count = usize::from(u16::from_be_bytes([bytes[768], bytes[769]]));
+            let transparent = usize::from(u16::from_be_bytes([bytes[770], bytes[771]]));
+            (count.min(256), (transparent != 0xFFFF).then_some(transparent))
+        }
+        len => {
+            return Err(PaletteError::Malformed {
+                format,
+                reason: format!("expected 768 or 772 bytes, found {len}"),
+            })
+        }
+    };
+
+    Ok(bytes[..count * 3]
+        .chunks_exact(3)
+        .enumerate()
+        .map(|(i, rgb)| {
+            let alpha = if transparent == Some(i) { 0 } else { 255 };
+            entry([rgb[0], rgb[1], rgb[2], alpha], None)
+        })
+        .collect())
+}
+
+/// Reads big endian values off the front of an `.ase` file.
+struct AseReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> AseReader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], PaletteError> {
+        if self.bytes.len() < n {
+            return Err(PaletteError::Malformed {
+                format: PaletteFormat::Ase,
+                reason: "unexpected end of file".to_string(),
+            });
+        }
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn u16(&mut self) -> Result<u16, PaletteError> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, PaletteError> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn f32(&mut self) -> Result<f32, PaletteError> {
+        let b = self.take(4)?;
+        Ok(f32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+}
+
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn unit_to_u8(value: f32) -> u8 {
+    (value.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+pub(super) fn read_ase(bytes: &[u8]) -> Result<Vec<PaletteEntry>, PaletteError> {
+    let format = PaletteFormat::Ase;
+    let mut reader = AseReader { bytes };
+    if reader.take(4)? != b"ASEF" {
+        return Err(PaletteError::MissingHeader { format });
+    }
+    let _version = (reader.u16()?, reader.u16()?);
+    let blocks = reader.u32()?;
+
+    let mut entries = Vec::new();
+    for _ in 0..blocks {
+        let kind = reader.u16()?;
+        let len = reader.u32()? as usize;
+        let mut block = AseReader {
+            bytes: reader.take(len)?,
+        };
+        // group starts and ends only matter for nesting, which a flat palette can't keep
+        if kind != 0x0001 {
+            continue;
+        }
+
+        // the name is UTF-16 with a terminating zero, its length counts code units
+        let name_len = usize::from(block.u16()?);
+        let name: Vec<u16> = block
+            .take(name_len * 2)?
+            .chunks_exact(2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .collect();
+        let name = String::from_utf16_lossy(&name).trim_end_matches('\0').to_string();
+
+        let color = match block.take(4)? {
+            b"RGB " => [
+                unit_to_u8(block.f32()?),
+                unit_to_u8(block.f32()?),
+                unit_to_u8(block.f32()?),
+                255,
+            ],
+            b"CMYK" => {
+                let (c, m, y, k) = (block.f32()?, block.f32()?, block.f32()?, block.f32()?);
+                [
+                    unit_to_u8((1.0 - c) * (1.0 - k)),
+                    unit_to_u8((1.0 - m) * (1.0 - k)),
+                    unit_to_u8((1.0 - y) * (1.0 - k)),
+                    255,
+                ]
+            }
+            b"LAB " => {
+                // lightness is stored from 0 to 1, relative to a D50 white
+                let lab = Lab::<D50, f32>::new(block.f32()? * 100.0, block.f32()?, block.f32()?);
+                let rgb = Srgb::<f32>::adapt_from(lab);
+                [
+                    unit_to_u8(rgb.red),
+                    unit_to_u8(rgb.green),
+                    unit_to_u8(rgb.blue),
+                    255,
+                ]
+            }
+            b"Gray" => {
+                let v = unit_to_u8(block.f32()?);
+                [v, v, v, 255]
+            }
+            model => {
+                return Err(PaletteError::Malformed {
+                    format,
+                    reason: format!(
+                        "unsupported color model {:?}",
+                        String::from_utf8_lossy(model)
+                    ),
+                })
+            }
+        };
+        entries.push(entry(color, (!name.is_empty()).then_some(name)));
+    }
+    Ok(entries)
+}
+
+pub(super) fn read_paint_net(bytes: &[u8]) -> Result<Vec<PaletteEntry>, PaletteError> {
+    let format = PaletteFormat::PaintNet;
+    let mut entries = Vec::new();
+    for (line, text) in numbered_lines(text(bytes, format)?) {
+        if text.starts_with(';') {
+            continue;
+        }
+
+        let color = match (text.len(), parse_hex(text)) {
+            (8, Some(argb)) => {
+                let [a, r, g, b] = argb.to_be_bytes();
+                [r, g, b, a]
+            }
+            (6, Some(rgb)) => {
+                let [_, r, g, b] = rgb.to_be_bytes();
+                [r, g, b, 255]
+            }
+            _ => {
+                return Err(PaletteError::InvalidLine {
+                    format,
+                    line,
+                    text: text.to_string(),
+                })
+            }
+        };
+        entries.push(entry(color, None));
+    }
+    Ok(entries)
+}
+
+pub(super) fn read_hex(bytes: &[u8]) -> Result<Vec<PaletteEntry>, PaletteError> {
+    let format = PaletteFormat::Hex;
+    let mut entries = Vec::new();
+    for (line, text) in numbered_lines(text(bytes, format)?) {
+        let digits = text.strip_prefix('#').unwrap_or(text);
+        let color = match (digits.len(), parse_hex(digits)) {
+            (6, Some(rgb)) => {
+                let [_, r, g, b] = rgb.to_be_bytes();
+                [r, g, b, 255]
+            }
+            (8, Some(rgba)) => rgba.to_be_bytes(),
+            _ => {
+                return Err(PaletteError::InvalidLine {
+                    format,
+                    line,
+                    text: text.to_string(),
+                })
+            }
+        };
+        entries.push(entry(color, None));
+    }
+    Ok(entries)
+}
+
+/// Every distinct color of the image in scan order. Fully transparent pixels are taken as the
+/// background between swatches and skipped. Swatch images are small, so anything past the default
+/// decode limits is turned away before it's decoded.
+pub(super) fn read_png(bytes: &[u8]) -> Result<Vec<PaletteEntry>, PaletteError> {
+    let image = decode_image(bytes, ImageFormat::Png, &DecodeLimits::default()).map_err(|e| {
+        PaletteError::Malformed {
+            format: PaletteFormat::Png,
+            reason: e.to_string(),
+        }
+    })?;
+
+    let mut seen = HashSet::new();
+    Ok(image
+        .pixels()
+        .map(|(_, _, pixel)| pixel.0)
+        .filter(|rgba| rgba[3] != 0 && seen.insert(*rgba))
+        .map(|rgba| entry(rgba, None))
+        .collect())
+}
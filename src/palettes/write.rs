@@ -0,0 +1,177 @@
+use std::fmt::Write;
+use std::io::{BufWriter, Cursor};
+
+use image::{write_buffer_with_format, ColorType, ImageBuffer, ImageFormat};
+
+use super::{PaletteEntry, PaletteError, PaletteFormat};
+
+fn has_alpha(entries: &[PaletteEntry]) -> bool {
+    entries.iter().any(|e| e.color.alpha != 255)
+}
+
+/// Names end up on a single line of a text format, so line breaks can't stay.
+fn single_line(name: &str) -> String {
+    name.lines().map(str::trim).collect::<Vec<_>>().join(" ")
+}
+
+pub(super) fn write_gpl(entries: &[PaletteEntry]) -> Vec<u8> {
+    let alpha = has_alpha(entries);
+    let mut out = String::from("GIMP Palette\n");
+    if alpha {
+        out.push_str("Channels: RGBA\n");
+    }
+    out.push_str("#\n");
+
+    for PaletteEntry { color, name } in entries {
+        let _ = write!(out, "{:3} {:3} {:3}", color.red, color.green, color.blue);
+        if alpha {
+            let _ = write!(out, " {:3}", color.alpha);
+        }
+        if let Some(name) = name {
+            let _ = write!(out, "\t{}", single_line(name));
+        }
+        out.push('\n');
+    }
+    out.into_bytes()
+}
+
+/// JASC-PAL has no room for names. Alpha is written as a fourth value, like Aseprite does, but
+/// only if some color needs it.
+pub(super) fn write_jasc_pal(entries: &[PaletteEntry]) -> Vec<u8> {
+    let alpha = has_alpha(entries);
+    let mut out = format!("JASC-PAL\r\n0100\r\n{}\r\n", entries.len());
+    for PaletteEntry { color, .. } in entries {
+        let _ = write!(out, "{} {} {}", color.red, color.green, color.blue);
+        if alpha {
+            let _ = write!(out, " {}", color.alpha);
+        }
+        out.push_str("\r\n");
+    }
+    out.into_bytes()
+}
+
+/// 256 RGB triplets, followed by the number of colors in use and the first fully transparent
+/// one. Names and partial alpha get lost.
+pub(super) fn write_act(entries: &[PaletteEntry]) -> Result<Vec<u8>, PaletteError> {
+    let count = u16::try_from(entries.len())
+        .ok()
+        .filter(|count| *count <= 256)
+        .ok_or(PaletteError::TooManyColors {
+            format: PaletteFormat::Act,
+            max: 256,
+        })?;
+    let transparent = entries
+        .iter()
+        .position(|e| e.color.alpha == 0)
+        .and_then(|i| u16::try_from(i).ok())
+        .unwrap_or(0xFFFF);
+
+    let mut out = vec![0; 772];
+    for (rgb, PaletteEntry { color, .. }) in out.chunks_exact_mut(3).zip(entries) {
+        rgb.copy_from_slice(&[color.red, color.green, color.blue]);
+    }
+    out[768..770].copy_from_slice(&count.to_be_bytes());
+    out[770..772].copy_from_slice(&transparent.to_be_bytes());
+    Ok(out)
+}
+
+/// Every color as a global RGB swatch, outside of any group.
+pub(super) fn write_ase(entries: &[PaletteEntry]) -> Result<Vec<u8>, PaletteError> {
+    let too_many = || PaletteError::TooManyColors {
+        format: PaletteFormat::Ase,
+        max: u32::MAX as usize,
+    };
+
+    let mut out = b"ASEF".to_vec();
+    out.extend(1u16.to_be_bytes());
+    out.extend(0u16.to_be_bytes());
+    out.extend(u32::try_from(entries.len()).map_err(|_| too_many())?.to_be_bytes());
+
+    for PaletteEntry { color, name } in entries {
+        let name: Vec<u16> = name
+            .as_deref()
+            .unwrap_or_default()
+            .encode_utf16()
+            .take(u16::MAX as usize - 1)
+            .chain([0])
+            .collect();
+
+        let mut block = Vec::new();
+        #[allow(clippy::cast_possible_truncation)]
+        block.extend((name.len() as u16).to_be_bytes());
+        for unit in name {
+            block.extend(unit.to_be_bytes());
+        }
+        block.extend(b"RGB ");
+        for channel in [color.red, color.green, color.blue] {
+            block.extend((f32::from(channel) / 255.0).to_be_bytes());
+        }
+        // color type: global, spot, normal. normal it is
+        block.extend(2u16.to_be_bytes());
+
+        out.extend(0x0001u16.to_be_bytes());
+        #[allow(clippy::cast_possible_truncation)]
+        out.extend((block.len() as u32).to_be_bytes());
+        out.extend(block);
+    }
+    Ok(out)
+}
+
+pub(super) fn write_paint_net(entries: &[PaletteEntry]) -> Vec<u8> {
+    let mut out = String::from(";paint.net Palette File\n");
+    for PaletteEntry { color, .. } in entries {
+        let _ = writeln!(
+            out,
+            "{:02X}{:02X}{:02X}{:02X}",
+            color.alpha, color.red, color.green, color.blue
+        );
+    }
+    out.into_bytes()
+}
+
+/// Lowercase `rrggbb` lines like Lospec hands them out, or `#rrggbbaa` if some color needs alpha.
+/// Bare 8-digit lines would be detected as Paint.NET's `AARRGGBB`.
+pub(super) fn write_hex(entries: &[PaletteEntry]) -> Vec<u8> {
+    let alpha = has_alpha(entries);
+    let mut out = String::new();
+    for PaletteEntry { color, .. } in entries {
+        if alpha {
+            out.push('#');
+        }
+        let _ = write!(out, "{:02x}{:02x}{:02x}", color.red, color.green, color.blue);
+        if alpha {
+            let _ = write!(out, "{:02x}", color.alpha);
+        }
+        out.push('\n');
+    }
+    out.into_bytes()
+}
+
+/// A single row of pixels, one per color.
+pub(super) fn write_png(entries: &[PaletteEntry]) -> Result<Vec<u8>, PaletteError> {
+    let format = PaletteFormat::Png;
+    let width = u32::try_from(entries.len()).map_err(|_| PaletteError::TooManyColors {
+        format,
+        max: u32::MAX as usize,
+    })?;
+
+    let imgbuf: ImageBuffer<image::Rgba<u8>, Vec<_>> = ImageBuffer::from_fn(width, 1, |x, _| {
+        let color = entries[x as usize].color;
+        image::Rgba([color.red, color.green, color.blue, color.alpha])
+    });
+
+    let mut output_image = Cursor::new(Vec::new());
+    write_buffer_with_format(
+        &mut BufWriter::new(&mut output_image),
+        &imgbuf,
+        imgbuf.width(),
+        imgbuf.height(),
+        ColorType::Rgba8,
+        ImageFormat::Png,
+    )
+    .map_err(|e| PaletteError::Encode {
+        format,
+        reason: e.to_string(),
+    })?;
+    Ok(output_image.into_inner())
+}
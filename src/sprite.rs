@@ -1,4 +1,8 @@
-use crate::Color;
+#![allow(clippy::module_name_repetitions)]
+
+use crate::{Color, Error, TRANSPARENT};
+
+use wasm_bindgen::{prelude::*, Clamped};
 
 #[derive(Clone, Default)]
 pub struct Sprite {
@@ -18,4 +22,313 @@ impl Sprite {
             *col = color;
         }
     }
+
+    /// The `width` by `height` pixels starting at `x`, `y`, which have to be within the sprite.
+    #[must_use]
+    pub fn crop(&self, x: usize, y: usize, width: usize, height: usize) -> Sprite {
+        Sprite {
+            width,
+            height,
+            data: self
+                .data
+                .chunks_exact(self.width)
+                .skip(y)
+                .take(height)
+                .flat_map(|row| &row[x..x + width])
+                .copied()
+                .collect(),
+        }
+    }
+
+    /// Takes RGBA8 pixels in rows from the top, like canvas `ImageData`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an `InvalidOptions` error if `data` doesn't hold
+    /// `width * height * 4` bytes.
+    pub fn from_rgba(data: &[u8], width: usize, height: usize) -> Result<Sprite, Error> {
+        check_rgba_len(data.len(), width, height)?;
+        Ok(Sprite {
+            width,
+            height,
+            data: data
+                .chunks_exact(4)
+                .map(|p| Color::new(p[0], p[1], p[2], p[3]))
+                .collect(),
+        })
+    }
+
+    /// The pixels as RGBA8, in rows from the top.
+    #[must_use]
+    pub fn to_rgba(&self) -> Vec<u8> {
+        self.data
+            .iter()
+            .flat_map(|c| [c.red, c.green, c.blue, c.alpha])
+            .collect()
+    }
+}
+
+fn check_rgba_len(len: usize, width: usize, height: usize) -> Result<(), Error> {
+    if width.checked_mul(height).and_then(|n| n.checked_mul(4)) != Some(len) {
+        return Err(Error::InvalidOptions(format!(
+            "{len} bytes of RGBA don't make a {width}x{height} image"
+        )));
+    }
+    Ok(())
+}
+
+/// Plain RGBA8 pixels, for drawing straight onto a canvas without going through a PNG.
+#[wasm_bindgen]
+#[derive(Clone, Default)]
+pub struct RawImage {
+    width: usize,
+    height: usize,
+    data: Vec<u8>,
+}
+
+#[wasm_bindgen]
+impl RawImage {
+    /// # Errors
+    ///
+    /// This function will return an `InvalidOptions` error if `data` doesn't hold
+    /// `width * height * 4` bytes.
+    #[wasm_bindgen(constructor)]
+    pub fn new(data: Vec<u8>, width: usize, height: usize) -> Result<RawImage, Error> {
+        check_rgba_len(data.len(), width, height)?;
+        Ok(RawImage {
+            width,
+            height,
+            data,
+        })
+    }
+
+    #[wasm_bindgen(getter)]
+    #[must_use]
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    #[wasm_bindgen(getter)]
+    #[must_use]
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// A copy of the pixels.
+    #[wasm_bindgen(getter)]
+    #[must_use]
+    pub fn data(&self) -> Vec<u8> {
+        self.data.clone()
+    }
+
+    /// A copy of the pixels as a `Uint8ClampedArray`, for `new ImageData(data, width, height)`.
+    #[must_use]
+    pub fn clamped_data(&self) -> Clamped<Vec<u8>> {
+        Clamped(self.data.clone())
+    }
+
+    /// The pixels without copying them, for copying them somewhere else. The view is only valid
+    /// until this image is freed or the WASM memory grows, which any call into the library can
+    /// do, so use it right away. This crate is built with atomics, so its memory is always shared
+    /// and browsers won't build `ImageData` on the view, use `clamped_data` for that.
+    #[cfg(target_arch = "wasm32")]
+    #[must_use]
+    pub fn view(&self) -> js_sys::Uint8ClampedArray {
+        // SAFETY: the view borrows `data`, the docs above tell JS how long that holds
+        unsafe { js_sys::Uint8ClampedArray::view(&self.data) }
+    }
+}
+
+impl RawImage {
+    #[must_use]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    #[must_use]
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+impl From<&IndexedSprite> for RawImage {
+    fn from(sprite: &IndexedSprite) -> Self {
+        RawImage {
+            width: sprite.width,
+            height: sprite.height,
+            data: sprite.to_rgba(),
+        }
+    }
+}
+
+impl From<&Sprite> for RawImage {
+    fn from(sprite: &Sprite) -> Self {
+        RawImage {
+            width: sprite.width,
+            height: sprite.height,
+            data: sprite.to_rgba(),
+        }
+    }
+}
+
+/// Palette indices, one byte per pixel when the palette fits.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Indices {
+    U8(Vec<u8>),
+    U16(Vec<u16>),
+}
+
+impl Indices {
+    #[must_use]
+    pub fn get(&self, i: usize) -> Option<usize> {
+        match self {
+            Indices::U8(data) => data.get(i).map(|&index| usize::from(index)),
+            Indices::U16(data) => data.get(i).map(|&index| usize::from(index)),
+        }
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        match self {
+            Indices::U8(data) => data.len(),
+            Indices::U16(data) => data.len(),
+        }
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len()).filter_map(|i| self.get(i))
+    }
+}
+
+impl Default for Indices {
+    fn default() -> Self {
+        Indices::U8(Vec::new())
+    }
+}
+
+/// An image whose pixels are indices into its palette, which is what dithering produces.
+#[derive(Clone, Default)]
+pub struct IndexedSprite {
+    pub width: usize,
+    pub height: usize,
+    pub indices: Indices,
+    /// The palette the image was dithered with, followed by a fully transparent entry if any pixel
+    /// fell below the alpha threshold.
+    pub palette: Vec<Color>,
+}
+
+impl IndexedSprite {
+    /// Stores the indices in bytes if the palette has no more than 256 entries.
+    #[must_use]
+    pub fn new(width: usize, height: usize, indices: Vec<u16>, palette: Vec<Color>) -> Self {
+        let indices = if palette.len() <= 256 {
+            #[allow(clippy::cast_possible_truncation)]
+            Indices::U8(indices.iter().map(|&index| index as u8).collect())
+        } else {
+            Indices::U16(indices)
+        };
+
+        IndexedSprite {
+            width,
+            height,
+            indices,
+            palette,
+        }
+    }
+
+    /// Like `new`, but index `palette.len()` stands for a transparent pixel, and the transparent
+    /// entry only gets added if it's used.
+    pub(crate) fn with_transparent(
+        width: usize,
+        height: usize,
+        indices: Vec<u16>,
+        palette: &[Color],
+    ) -> Self {
+        let mut palette = palette.to_vec();
+        if indices.iter().any(|&index| usize::from(index) == palette.len()) {
+            palette.push(TRANSPARENT);
+        }
+        IndexedSprite::new(width, height, indices, palette)
+    }
+
+    /// Points the indices at `palette` instead, through `mapping` from old to new indices. Indices
+    /// past the end of `mapping` become the transparent entry.
+    pub(crate) fn remap(&self, mapping: &[usize], palette: &[Color]) -> Self {
+        // palettes are validated to have fewer than u16::MAX entries
+        #[allow(clippy::cast_possible_truncation)]
+        let indices = self
+            .indices
+            .iter()
+            .map(|index| mapping.get(index).copied().unwrap_or(palette.len()) as u16)
+            .collect();
+        IndexedSprite::with_transparent(self.width, self.height, indices, palette)
+    }
+
+    #[must_use]
+    pub fn get_index(&self, x: usize, y: usize) -> Option<usize> {
+        self.indices.get(y * self.width + x)
+    }
+
+    /// Does nothing if the pixel is out of bounds or `index` doesn't fit the index type.
+    pub fn set_index(&mut self, x: usize, y: usize, index: usize) {
+        let i = y * self.width + x;
+        match &mut self.indices {
+            Indices::U8(data) => {
+                if let (Some(pixel), Ok(index)) = (data.get_mut(i), u8::try_from(index)) {
+                    *pixel = index;
+                }
+            }
+            Indices::U16(data) => {
+                if let (Some(pixel), Ok(index)) = (data.get_mut(i), u16::try_from(index)) {
+                    *pixel = index;
+                }
+            }
+        }
+    }
+
+    #[must_use]
+    pub fn get_pixel(&self, x: usize, y: usize) -> Option<Color> {
+        self.palette.get(self.get_index(x, y)?).copied()
+    }
+
+    /// The pixels as RGBA8, in rows from the top. Indices past the end of the palette become
+    /// transparent.
+    #[must_use]
+    pub fn to_rgba(&self) -> Vec<u8> {
+        let rgba: Vec<[u8; 4]> = self
+            .palette
+            .iter()
+            .map(|c| [c.red, c.green, c.blue, c.alpha])
+            .collect();
+        let lookup = |index: usize| rgba.get(index).copied().unwrap_or([0; 4]);
+        let mut data = Vec::with_capacity(self.indices.len() * 4);
+        match &self.indices {
+            Indices::U8(indices) => {
+                data.extend(indices.iter().flat_map(|&index| lookup(usize::from(index))));
+            }
+            Indices::U16(indices) => {
+                data.extend(indices.iter().flat_map(|&index| lookup(usize::from(index))));
+            }
+        }
+        data
+    }
+
+    /// The image in full colors. Indices past the end of the palette become transparent.
+    #[must_use]
+    pub fn to_sprite(&self) -> Sprite {
+        Sprite {
+            width: self.width,
+            height: self.height,
+            data: self
+                .indices
+                .iter()
+                .map(|index| self.palette.get(index).copied().unwrap_or(TRANSPARENT))
+                .collect(),
+        }
+    }
 }